@@ -0,0 +1,40 @@
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Makes the git commit and the time of the build available to the server, for the `/version`
+/// endpoint.
+fn main() {
+    // Builds from a source archive have no git repository.
+    let commit = Command::new("git").args(&["rev-parse", "HEAD"]).output().ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=ATTOLYTICS_GIT_COMMIT={}", commit);
+    // Run again when a commit is checked out or made. Listing any file replaces the default of
+    // running again whenever a file in the package changes, so the sources are listed as well.
+    println!("cargo:rerun-if-changed=src");
+    if let Some(git_dir) = git_dir() {
+        let head = git_dir.join("HEAD");
+        println!("cargo:rerun-if-changed={}", head.display());
+        // A branch whose ref was packed has no file of its own; it changes once it is committed to.
+        let reference = fs::read_to_string(&head).ok()
+            .and_then(|head| head.strip_prefix("ref: ").map(|reference| git_dir.join(reference.trim())))
+            .filter(|reference| reference.exists());
+        if let Some(reference) = reference {
+            println!("cargo:rerun-if-changed={}", reference.display());
+        }
+    }
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    println!("cargo:rustc-env=ATTOLYTICS_BUILD_TIMESTAMP={}", timestamp);
+}
+
+/// Returns the git directory of the repository, if this is built from one.
+fn git_dir() -> Option<PathBuf> {
+    Command::new("git").args(&["rev-parse", "--git-dir"]).output().ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|git_dir| PathBuf::from(git_dir.trim()))
+}
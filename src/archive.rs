@@ -0,0 +1,98 @@
+use std::fmt::{self, Display};
+use std::io::{self, Write};
+
+use aws_sdk_s3::Client;
+use aws_sdk_s3::error::DisplayErrorContext;
+use aws_sdk_s3::primitives::ByteStream;
+use chrono::{DateTime, Utc};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+
+use crate::queue::RetryQueue;
+
+/// Maximum number of batches waiting to be uploaded. Beyond this, batches are dropped.
+const QUEUE_SIZE: usize = 1000;
+/// Number of times to try uploading each batch.
+const MAX_ATTEMPTS: u32 = 5;
+
+#[derive(Debug)]
+struct Batch {
+    app_id: String,
+    batch_id: String,
+    received_at: DateTime<Utc>,
+    events: Vec<serde_json::Value>,
+}
+
+impl Display for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "batch {} of app {} with {} events", self.batch_id, self.app_id, self.events.len())
+    }
+}
+
+/// Archives inserted batches of events to an Amazon S3 bucket, one gzipped newline-delimited JSON
+/// object per batch, from a background thread.
+#[derive(Debug)]
+pub struct Archive {
+    queue: RetryQueue<Batch>,
+}
+
+impl Archive {
+    /// Creates the queue and starts the thread that uploads from it. Credentials and the region are
+    /// taken from the usual AWS environment variables and configuration files.
+    pub fn start(bucket: &str, prefix: &str) -> Result<Archive, io::Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let client = Client::new(&runtime.block_on(aws_config::load_from_env()));
+        let (bucket, prefix) = (bucket.to_string(), prefix.to_string());
+        let queue = RetryQueue::start("archive", QUEUE_SIZE, MAX_ATTEMPTS, move |batch: &Batch| {
+            let key = object_key(&prefix, &batch.app_id, batch.received_at, &batch.batch_id);
+            let body = gzip_ndjson(&batch.events).map_err(|err| format!("failed to compress {}: {}", key, err))?;
+            runtime.block_on(client.put_object()
+                    .bucket(&bucket)
+                    .key(&key)
+                    .content_type("application/gzip")
+                    .body(ByteStream::from(body))
+                    .send())
+                .map(|_| ())
+                .map_err(|err| format!("failed to upload {} to bucket {}: {}", key, bucket, DisplayErrorContext(&err)))
+        });
+        Ok(Archive { queue })
+    }
+
+    /// Queues the events, which were inserted together, for uploading as a single object.
+    pub fn send(&self, app_id: &str, batch_id: &str, received_at: DateTime<Utc>, events: Vec<serde_json::Value>) {
+        if events.is_empty() {
+            return;
+        }
+        self.queue.push(Batch { app_id: app_id.to_string(), batch_id: batch_id.to_string(), received_at, events });
+    }
+}
+
+/// Returns the key of the object for a batch, partitioned by app and by the hour in which the batch
+/// was received, e.g. `prefix/myapp/2019/04/01/14/<batch_id>.ndjson.gz`.
+fn object_key(prefix: &str, app_id: &str, received_at: DateTime<Utc>, batch_id: &str) -> String {
+    format!("{}{}/{}/{}.ndjson.gz", prefix, app_id, received_at.format("%Y/%m/%d/%H"), batch_id)
+}
+
+fn gzip_ndjson(events: &[serde_json::Value]) -> Result<Vec<u8>, io::Error> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    for event in events {
+        serde_json::to_writer(&mut encoder, event)?;
+        encoder.write_all(b"\n")?;
+    }
+    encoder.finish()
+}
+
+#[test]
+fn archived_objects() {
+    use std::io::Read;
+
+    let received_at = DateTime::parse_from_rfc3339("2019-04-01T14:49:40Z").unwrap().with_timezone(&Utc);
+    assert_eq!(object_key("attolytics/", "myapp", received_at, "batch"), "attolytics/myapp/2019/04/01/14/batch.ndjson.gz");
+
+    let events = vec![serde_json::json!({"_t": "events", "a": 1}), serde_json::json!({"_t": "events", "a": 2})];
+    let mut ndjson = String::new();
+    flate2::read::GzDecoder::new(&gzip_ndjson(&events).unwrap()[..]).read_to_string(&mut ndjson).unwrap();
+    assert_eq!(ndjson, "{\"_t\":\"events\",\"a\":1}\n{\"_t\":\"events\",\"a\":2}\n");
+}
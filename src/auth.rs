@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use hmac::{Hmac, Mac};
+use linked_hash_map::LinkedHashMap;
+use rocket::State;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use sha2::Sha256;
+use subtle::ConstantTimeEq;
+
+use crate::schema::ReplayProtection;
+
+/// Request header containing the app's secret key, for request bodies that have no room for it.
+pub const KEY_HEADER: &str = "X-Attolytics-Key";
+/// Request header containing the hex-encoded HMAC-SHA256 of the request body, keyed with the
+/// app's secret key.
+pub const SIGNATURE_HEADER: &str = "X-Attolytics-Signature";
+/// Request header containing the time the request was made, in seconds since the Unix epoch.
+pub const TIMESTAMP_HEADER: &str = "X-Attolytics-Timestamp";
+/// Request header containing a string that is unique to this request.
+pub const NONCE_HEADER: &str = "X-Attolytics-Nonce";
+
+/// Request header containing the key given by `--admin-key`, for the administrative endpoints.
+pub const ADMIN_KEY_HEADER: &str = "X-Attolytics-Admin-Key";
+
+/// Returns whether `signature` is the hex-encoded HMAC-SHA256, under `key`, of the concatenation
+/// of the given message parts. The comparison is done in constant time.
+pub fn verify_signature(key: &str, message: &[&[u8]], signature: &str) -> bool {
+    let signature = match hex::decode(signature.trim()) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    let mut mac = Hmac::<Sha256>::new_from_slice(key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    for part in message {
+        mac.update(part);
+    }
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Request guard for the app's secret key, taken from the `X-Attolytics-Key` header. Fails with
+/// status 401 if the header is missing; the key still needs to be verified against the app.
+#[derive(Debug)]
+pub struct KeyHeader(pub String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for KeyHeader {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one(KEY_HEADER) {
+            Some(key) => Outcome::Success(KeyHeader(key.to_string())),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// The key given by `--admin-key`, if any. Without it, the administrative endpoints are disabled.
+#[derive(Clone, Debug)]
+pub struct AdminKey(pub Option<String>);
+
+/// Request guard that only succeeds if the request has the correct admin key. If no admin key is
+/// configured, the request is forwarded, so that it ends up as a 404.
+#[derive(Debug)]
+pub struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let admin_key = match request.guard::<State<AdminKey>>().succeeded().and_then(|admin_key| admin_key.inner().0.as_ref()) {
+            Some(admin_key) => admin_key,
+            None => return Outcome::Forward(()),
+        };
+        match request.headers().get_one(ADMIN_KEY_HEADER) {
+            Some(key) if bool::from(key.as_bytes().ct_eq(admin_key.as_bytes())) => Outcome::Success(Admin),
+            Some(_) => Outcome::Failure((Status::Forbidden, ())),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+/// Remembers recently used nonces for each app, so that replayed requests can be detected. Clones
+/// share the same nonces.
+#[derive(Clone, Debug, Default)]
+pub struct NonceCache {
+    // Maps app ID to a map from nonce to the time it was received, in order of receipt.
+    nonces: Arc<Mutex<HashMap<String, LinkedHashMap<String, i64>>>>,
+}
+
+impl NonceCache {
+    /// Returns whether a request with the given timestamp and nonce is fresh: the timestamp is
+    /// within the allowed clock skew from `now`, and the nonce has not been used before. If so,
+    /// the nonce is recorded so that it will be rejected next time.
+    pub fn check(&self, app_id: &str, config: &ReplayProtection, timestamp: i64, nonce: &str, now: i64) -> bool {
+        let max_clock_skew = config.max_clock_skew as i64;
+        if (now - timestamp).abs() > max_clock_skew {
+            return false;
+        }
+        let mut nonces = self.nonces.lock().unwrap();
+        let nonces = nonces.entry(app_id.to_string()).or_default();
+        // A request received at time t has a timestamp of at most t + max_clock_skew, so it would
+        // be rejected after t + 2 * max_clock_skew anyway, and we can forget its nonce.
+        while nonces.front().map_or(false, |(_, &received)| now - received > 2 * max_clock_skew) {
+            nonces.pop_front();
+        }
+        if nonces.contains_key(nonce) {
+            return false;
+        }
+        nonces.insert(nonce.to_string(), now);
+        while nonces.len() > config.nonce_cache_size {
+            nonces.pop_front();
+        }
+        true
+    }
+}
+
+#[test]
+fn verify_signature_rfc_4231() {
+    // Test case 2 from RFC 4231.
+    let body = b"what do ya want for nothing?";
+    let signature = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+    assert!(verify_signature("Jefe", &[body], signature));
+    assert!(verify_signature("Jefe", &[b"what do ya ", b"want for nothing?"], signature));
+    assert!(verify_signature("Jefe", &[body], &signature.to_uppercase()));
+    assert!(!verify_signature("Jeff", &[body], signature));
+    assert!(!verify_signature("Jefe", &[b"what do ya want for something?"], signature));
+    assert!(!verify_signature("Jefe", &[body], "not hex"));
+    assert!(!verify_signature("Jefe", &[body], ""));
+}
+
+#[test]
+fn nonce_cache_rejects_replays() {
+    let config = ReplayProtection { max_clock_skew: 60, nonce_cache_size: 2 };
+    let cache = NonceCache::default();
+    assert!(cache.check("app", &config, 1000, "a", 1000));
+    assert!(!cache.check("app", &config, 1000, "a", 1001));
+    assert!(cache.check("other_app", &config, 1000, "a", 1001));
+    assert!(!cache.check("app", &config, 900, "b", 1000));
+    assert!(!cache.check("app", &config, 1100, "b", 1000));
+    assert!(cache.check("app", &config, 1059, "b", 1000));
+}
+
+#[test]
+fn nonce_cache_is_bounded() {
+    let config = ReplayProtection { max_clock_skew: 60, nonce_cache_size: 2 };
+    let cache = NonceCache::default();
+    assert!(cache.check("app", &config, 1000, "a", 1000));
+    assert!(cache.check("app", &config, 1000, "b", 1000));
+    assert!(cache.check("app", &config, 1000, "c", 1000));
+    assert_eq!(cache.nonces.lock().unwrap()["app"].len(), 2);
+    // Forgotten because the cache is full.
+    assert!(cache.check("app", &config, 1000, "a", 1000));
+}
+
+#[test]
+fn nonce_cache_forgets_expired_nonces() {
+    let config = ReplayProtection { max_clock_skew: 60, nonce_cache_size: 100 };
+    let cache = NonceCache::default();
+    assert!(cache.check("app", &config, 1000, "a", 1000));
+    assert!(cache.check("app", &config, 1200, "b", 1200));
+    assert_eq!(cache.nonces.lock().unwrap()["app"].len(), 1);
+}
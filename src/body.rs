@@ -0,0 +1,342 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{self, Read};
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
+
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::Request;
+use rocket::State;
+use serde::Deserialize;
+
+use crate::auth;
+
+/// Limit on the body size if none is configured for "json" in Rocket's limits.
+const DEFAULT_LIMIT: u64 = 1 << 20;
+
+/// The maximum time to spend reading a request body, as given by `--request-read-timeout`. If it
+/// is exceeded, the request fails with status 408, so that clients that send their body very
+/// slowly can't keep a worker thread busy for long.
+#[derive(Debug)]
+pub struct ReadTimeout(pub Option<Duration>);
+
+/// The maximum number of arrays and objects that a request body may nest inside each other, as
+/// given by `--max-json-depth`. Deeper bodies are rejected with status 400 before they are
+/// parsed, so they can't make the parser recurse deeply.
+#[derive(Debug)]
+pub struct MaxJsonDepth(pub usize);
+
+#[derive(Debug, Deserialize)]
+pub struct EventPostData {
+    pub secret_key: String,
+    pub events: Vec<serde_json::Value>,
+}
+
+/// Data guard for the body of an event POST request. Like `rocket_contrib::json::Json`, but it
+/// also keeps the raw bytes, which are needed to verify the request signature.
+///
+/// Three content types are accepted: `application/json`, containing an `EventPostData` object;
+/// `application/msgpack` (or `application/x-msgpack`), containing the same object encoded as
+/// MessagePack; and `application/x-ndjson`, containing one event object per line. In the latter
+/// case, the secret key is taken from the `X-Attolytics-Key` header. Requests with other content
+/// types are forwarded.
+#[derive(Debug)]
+pub struct EventBody {
+    pub raw: Vec<u8>,
+    pub data: EventPostData,
+}
+
+#[derive(Debug)]
+pub enum BodyError {
+    IoError(io::Error),
+    ParseError(serde_json::Error),
+    /// An error in one line of a newline-delimited JSON body, along with its (1-based) line number.
+    NdjsonParseError(usize, serde_json::Error),
+    MsgpackError(rmp_serde::decode::Error),
+    MissingKey,
+    TooDeep(usize),
+    TooLarge(u64),
+}
+
+impl BodyError {
+    /// Returns the status that the request fails with.
+    pub fn status(&self) -> Status {
+        match self {
+            // Rocket's own read timeout on the socket surfaces as `WouldBlock`.
+            BodyError::IoError(err) if err.kind() == io::ErrorKind::TimedOut || err.kind() == io::ErrorKind::WouldBlock =>
+                Status::RequestTimeout,
+            BodyError::TooLarge(_) => Status::PayloadTooLarge,
+            BodyError::ParseError(err) | BodyError::NdjsonParseError(_, err) if err.is_data() => Status::UnprocessableEntity,
+            _ => Status::BadRequest,
+        }
+    }
+
+    /// Returns the body of the error response. For JSON bodies that fail to parse, it includes
+    /// the line and column of the error, so that clients can find out what they sent wrong.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            BodyError::ParseError(err) =>
+                serde_json::json!({"error": self.to_string(), "line": err.line(), "column": err.column()}),
+            BodyError::NdjsonParseError(line, err) =>
+                serde_json::json!({"error": self.to_string(), "line": line, "column": err.column()}),
+            _ => serde_json::json!({"error": self.to_string()}),
+        }
+    }
+}
+
+enum Format {
+    Json,
+    Ndjson,
+    Msgpack,
+}
+
+impl Display for BodyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            BodyError::IoError(err) => write!(f, "error reading body: {}", err),
+            BodyError::ParseError(err) => write!(f, "error parsing body: {}", err),
+            BodyError::NdjsonParseError(line, err) => write!(f, "error parsing line {} of body: {}", line, err),
+            BodyError::MsgpackError(err) => write!(f, "error decoding msgpack body: {}", err),
+            BodyError::MissingKey => write!(f, "missing {} header", auth::KEY_HEADER),
+            BodyError::TooDeep(max_depth) => write!(f, "body is nested more than {} levels deep", max_depth),
+            BodyError::TooLarge(limit) => write!(f, "body is larger than {} bytes", limit),
+        }
+    }
+}
+
+impl Error for BodyError {}
+
+impl FromDataSimple for EventBody {
+    type Error = BodyError;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, Self::Error> {
+        let format = match request.content_type() {
+            Some(content_type) if content_type.is_json() => Format::Json,
+            Some(content_type) if content_type.top() == "application" => match content_type.sub().as_str() {
+                "x-ndjson" => Format::Ndjson,
+                "msgpack" | "x-msgpack" => Format::Msgpack,
+                _ => return Outcome::Forward(data),
+            },
+            _ => return Outcome::Forward(data),
+        };
+        let limit = request.limits().get("json").unwrap_or(DEFAULT_LIMIT);
+        let timeout = request.guard::<State<ReadTimeout>>().succeeded().and_then(|timeout| timeout.0);
+        // Read one byte more than the limit, to tell a body that is too large from one that fits
+        // exactly.
+        let mut reader = DeadlineReader {
+            inner: data.open().take(limit + 1),
+            deadline: timeout.map(|timeout| Instant::now() + timeout),
+        };
+        let mut raw = Vec::new();
+        if let Err(err) = reader.read_to_end(&mut raw) {
+            let err = BodyError::IoError(err);
+            return Outcome::Failure((err.status(), err));
+        }
+        if raw.len() as u64 > limit {
+            let err = BodyError::TooLarge(limit);
+            return Outcome::Failure((err.status(), err));
+        }
+        let max_depth = request.guard::<State<MaxJsonDepth>>().succeeded().map(|max_depth| max_depth.0);
+        let too_deep = match (&format, max_depth) {
+            (Format::Json, Some(max_depth)) | (Format::Ndjson, Some(max_depth)) => exceeds_depth(&raw, max_depth),
+            _ => false,
+        };
+        if too_deep {
+            let err = BodyError::TooDeep(max_depth.unwrap());
+            return Outcome::Failure((err.status(), err));
+        }
+        let parsed = match format {
+            Format::Json => serde_json::from_slice(&raw).map_err(BodyError::ParseError),
+            Format::Ndjson => match request.headers().get_one(auth::KEY_HEADER) {
+                Some(secret_key) => parse_ndjson(&raw)
+                    .map(|events| EventPostData { secret_key: secret_key.to_string(), events })
+                    .map_err(|(line, err)| BodyError::NdjsonParseError(line, err)),
+                None => Err(BodyError::MissingKey),
+            },
+            // MessagePack can't be scanned as cheaply, but the decoder has a depth limit of its own.
+            Format::Msgpack => rmp_serde::from_slice::<EventPostData>(&raw)
+                .map_err(BodyError::MsgpackError)
+                .and_then(|data| match max_depth {
+                    // The events are inside the body object and the events array.
+                    Some(max_depth) if data.events.iter().any(|event| 2 + value_depth(event) > max_depth) =>
+                        Err(BodyError::TooDeep(max_depth)),
+                    _ => Ok(data),
+                }),
+        };
+        match parsed {
+            Ok(data) => Outcome::Success(EventBody { raw, data }),
+            Err(err) => Outcome::Failure((err.status(), err)),
+        }
+    }
+}
+
+/// Fails reads once the deadline has passed. A single read can still block for as long as the
+/// socket's read timeout allows.
+struct DeadlineReader<R> {
+    inner: R,
+    deadline: Option<Instant>,
+}
+
+impl<R: Read> Read for DeadlineReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline =>
+                Err(io::Error::new(io::ErrorKind::TimedOut, "request body was not received in time")),
+            _ => self.inner.read(buf),
+        }
+    }
+}
+
+/// Returns whether JSON text nests arrays and objects more than `max_depth` levels deep. This only
+/// counts brackets outside of strings, so it is much cheaper than parsing. Invalid JSON may give
+/// a wrong answer, but then it fails to parse anyway.
+fn exceeds_depth(raw: &[u8], max_depth: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in raw {
+        if in_string {
+            match b {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => {}
+            }
+        } else {
+            match b {
+                b'"' => in_string = true,
+                b'[' | b'{' => {
+                    depth += 1;
+                    if depth > max_depth {
+                        return true;
+                    }
+                }
+                b']' | b'}' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+    }
+    false
+}
+
+/// Returns the number of arrays and objects that are nested inside each other in the value,
+/// including the value itself.
+fn value_depth(json: &serde_json::Value) -> usize {
+    match json {
+        serde_json::Value::Array(values) => 1 + values.iter().map(value_depth).max().unwrap_or(0),
+        serde_json::Value::Object(map) => 1 + map.values().map(value_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Parses newline-delimited JSON, skipping blank lines. On failure, also returns the (1-based)
+/// number of the line that could not be parsed.
+fn parse_ndjson(raw: &[u8]) -> Result<Vec<serde_json::Value>, (usize, serde_json::Error)> {
+    raw.split(|&b| b == b'\n')
+        .enumerate()
+        .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+        .map(|(index, line)| serde_json::from_slice(line).map_err(|err| (index + 1, err)))
+        .collect()
+}
+
+impl Deref for EventBody {
+    type Target = EventPostData;
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for EventBody {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+/// Responds with the parsed body, so that tests can post requests through the data guard.
+#[cfg(test)]
+#[post("/events", data = "<body>")]
+fn parsed_body(body: Result<EventBody, BodyError>) -> Result<String, Status> {
+    body.map(|body| serde_json::json!({"secret_key": body.secret_key, "events": body.events}).to_string())
+        .map_err(|err| err.status())
+}
+
+#[cfg(test)]
+fn parsed_body_client(json_limit: u64) -> rocket::local::Client {
+    let config = rocket::Config::build(rocket::config::Environment::Development)
+        .limits(rocket::config::Limits::new().limit("json", json_limit))
+        .finalize()
+        .unwrap();
+    rocket::local::Client::new(rocket::custom(config).mount("/", routes![parsed_body])).unwrap()
+}
+
+#[test]
+fn body_size_limit() {
+    let client = parsed_body_client(40);
+    let post = |body: &str| client.post("/events")
+        .header(rocket::http::ContentType::JSON)
+        .body(body)
+        .dispatch()
+        .status();
+    let body = r#"{"secret_key": "key", "events": []}"#;
+    assert_eq!(post(body), Status::Ok);
+    assert_eq!(post(&format!("{}{}", body, " ".repeat(40 - body.len()))), Status::Ok);
+    assert_eq!(post(&format!("{}{}", body, " ".repeat(41 - body.len()))), Status::PayloadTooLarge);
+}
+
+#[test]
+fn parse_ndjson_lines() {
+    let events = parse_ndjson(b"{\"_t\": \"a\"}\r\n\n  \n{\"_t\": \"b\"}").unwrap();
+    assert_eq!(events, vec![serde_json::json!({"_t": "a"}), serde_json::json!({"_t": "b"})]);
+    assert_eq!(parse_ndjson(b"{\"_t\": \"a\"}\n\n{").unwrap_err().0, 3);
+}
+
+#[test]
+fn parse_error_position() {
+    let err = BodyError::ParseError(serde_json::from_slice::<EventPostData>(b"{\n  \"secret_key\": \"key\",\n  \"events\": [}").unwrap_err());
+    assert_eq!(err.status(), Status::BadRequest);
+    let json = err.to_json();
+    assert_eq!((json["line"].as_u64(), json["column"].as_u64()), (Some(3), Some(14)));
+    assert!(json["error"].as_str().unwrap().starts_with("error parsing body: "));
+    let (line, err) = parse_ndjson(b"{}\n{\"a\": tru}").unwrap_err();
+    assert_eq!(BodyError::NdjsonParseError(line, err).to_json()["line"], 2);
+}
+
+#[test]
+fn json_nesting_depth() {
+    assert!(!exceeds_depth(br#"{"events": [{"a": [1]}]}"#, 4));
+    assert!(exceeds_depth(br#"{"events": [{"a": [[1]]}]}"#, 4));
+    assert!(!exceeds_depth(br#"{"a": "[[[[[\"{{{{"}"#, 1));
+    assert!(!exceeds_depth(b"{}\n{}\n[]", 1));
+    assert_eq!(value_depth(&serde_json::json!({"a": [1, {"b": {}}], "c": 1})), 4);
+    assert_eq!(value_depth(&serde_json::json!(1)), 0);
+}
+
+#[test]
+fn parse_msgpack_body() {
+    let client = parsed_body_client(DEFAULT_LIMIT);
+    let post = |content_type: &str, raw: Vec<u8>| {
+        let mut response = client.post("/events")
+            .header(rocket::http::Header::new("Content-Type", content_type.to_string()))
+            .body(raw)
+            .dispatch();
+        (response.status(), response.body_string())
+    };
+    let body = serde_json::json!({"secret_key": "key", "events": [{"_t": "a", "n": 1}]});
+    let raw = rmp_serde::to_vec_named(&body).unwrap();
+    assert_eq!(post("application/msgpack", raw.clone()), (Status::Ok, Some(body.to_string())));
+    assert_eq!(post("application/x-msgpack", raw.clone()), (Status::Ok, Some(body.to_string())));
+    assert_eq!(post("application/msgpack", raw[..raw.len() - 1].to_vec()).0, Status::BadRequest);
+    assert_eq!(post("application/octet-stream", raw).0, Status::NotFound);
+}
+
+#[test]
+fn deadline_reader_times_out() {
+    let mut raw = Vec::new();
+    let mut reader = DeadlineReader { inner: &b"{}"[..], deadline: None };
+    reader.read_to_end(&mut raw).unwrap();
+    assert_eq!(raw, b"{}");
+    let mut reader = DeadlineReader { inner: &b"{}"[..], deadline: Some(Instant::now()) };
+    assert_eq!(reader.read_to_end(&mut raw).unwrap_err().kind(), io::ErrorKind::TimedOut);
+}
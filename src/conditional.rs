@@ -0,0 +1,53 @@
+use rocket::outcome::Outcome;
+use rocket::request::{FromRequest, Request};
+use sha2::{Digest, Sha256};
+
+/// The `If-None-Match` header of a request, which lets clients that poll for events avoid
+/// downloading the same response again.
+#[derive(Debug, Default)]
+pub struct Preconditions {
+    if_none_match: Option<String>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Preconditions {
+    type Error = !;
+    fn from_request(request: &'a Request<'r>) -> rocket::request::Outcome<Self, Self::Error> {
+        Outcome::Success(Preconditions {
+            if_none_match: request.headers().get_one("If-None-Match").map(str::to_string),
+        })
+    }
+}
+
+impl Preconditions {
+    /// Returns whether the client already has the response with the given `ETag`, so it can be sent
+    /// status 304 instead.
+    pub fn not_modified(&self, etag: &str) -> bool {
+        self.if_none_match.as_ref().map_or(false, |if_none_match| if_none_match.split(',')
+            .map(|tag| tag.trim())
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag))
+    }
+}
+
+/// Returns a strong `ETag`, including the quotes, that identifies a response by its contents.
+pub fn etag(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_be_bytes());
+        hasher.update(part);
+    }
+    format!("\"{}\"", hex::encode(&hasher.finalize()[..16]))
+}
+
+#[test]
+fn conditional_requests() {
+    let tag = etag(&[&b"body"[..], &b""[..]]);
+    assert_ne!(tag, etag(&[&b"bod"[..], &b"y"[..]]));
+
+    assert!(!Preconditions::default().not_modified(&tag));
+    let preconditions = Preconditions { if_none_match: Some(format!("\"other\", W/{}", tag)) };
+    assert!(preconditions.not_modified(&tag));
+    let preconditions = Preconditions { if_none_match: Some("\"other\"".to_string()) };
+    assert!(!preconditions.not_modified(&tag));
+    let preconditions = Preconditions { if_none_match: Some("*".to_string()) };
+    assert!(preconditions.not_modified(&tag));
+}
@@ -1,16 +1,29 @@
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::net::IpAddr;
+use std::slice;
+use std::time::Duration;
 
+use chrono::{Datelike, DateTime, TimeZone, Utc};
 use itertools::Itertools;
 use postgres::GenericConnection;
 use postgres::types::ToSql;
+use r2d2::Pool;
+use r2d2_postgres::PostgresConnectionManager;
 use rocket::http::HeaderMap;
-use crate::schema::{Schema, Table};
+use rocket::request::FormItems;
+use crate::schema::{App, Column, FillIfAbsent, OnConflict, ROLLUP_BUCKET_COLUMN, ROLLUP_COUNT_COLUMN, Rollup, Schema, Source, Table};
 use std::fmt::Display;
 use std::error::Error;
-use crate::types::{ConversionError, header_to_sql};
+use crate::encryption::Encryption;
+use crate::geoip::GeoIp;
+use tracing::{trace, warn};
+use crate::types::{ConversionError, Type, check_f32_precision, header_to_sql, is_allowed, json_to_date_time, json_to_sql_literal, unwrap_if_required};
 
 #[derive(Debug)]
 pub enum DbError {
+    PoolError(r2d2::Error),
     PostgresError(postgres::Error),
     ConversionError(String, ConversionError),
     StructureError(String),
@@ -19,6 +32,7 @@ pub enum DbError {
 impl Display for DbError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         match self {
+            DbError::PoolError(err) => write!(f, "error connecting to database: {}", err),
             DbError::PostgresError(err) => write!(f, "{}", err),
             DbError::ConversionError(field, err) => write!(f, "error converting field \"{}\": {}", field, err),
             DbError::StructureError(msg) => write!(f, "{}", msg),
@@ -28,63 +42,839 @@ impl Display for DbError {
 
 impl Error for DbError {}
 
+impl DbError {
+    /// Returns a short name for the kind of error, which is logged along with it, so that log
+    /// pipelines can aggregate errors without parsing the message.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            _ if self.is_connection_error() => "connection",
+            _ if self.is_timeout() => "timeout",
+            DbError::PoolError(_) => "pool",
+            DbError::PostgresError(_) => "postgres",
+            DbError::ConversionError(_, _) => "conversion",
+            DbError::StructureError(_) => "structure",
+        }
+    }
+
+    /// Returns whether this error means the database could not be reached, rather than that
+    /// something was wrong with the query or the data.
+    pub fn is_connection_error(&self) -> bool {
+        match self {
+            DbError::PoolError(_) => true,
+            DbError::PostgresError(err) =>
+                err.as_io().is_some() ||
+                err.as_connection().is_some() ||
+                err.code().map_or(false, |code| code.code().starts_with("08") || code.code().starts_with("57P")),
+            _ => false,
+        }
+    }
+
+    /// Returns whether a statement was cancelled because it ran into the `statement_timeout`.
+    pub fn is_timeout(&self) -> bool {
+        match self {
+            // 57014 is "query_canceled".
+            DbError::PostgresError(err) => err.code().map_or(false, |code| code.code() == "57014"),
+            _ => false,
+        }
+    }
+
+    /// Returns whether this error was caused by invalid data in the event, such as a value that
+    /// is not one of those of an enum type, rather than by a problem on the server side.
+    pub fn is_invalid_data(&self) -> bool {
+        match self {
+            DbError::ConversionError(_, _) => true,
+            // Class 22 is "data exception".
+            DbError::PostgresError(err) => err.code().map_or(false, |code| code.code().starts_with("22")),
+            _ => false,
+        }
+    }
+}
+
+impl From<r2d2::Error> for DbError {
+    fn from(err: r2d2::Error) -> DbError {
+        DbError::PoolError(err)
+    }
+}
+
 impl From<postgres::Error> for DbError {
     fn from(err: postgres::Error) -> DbError {
         DbError::PostgresError(err)
     }
 }
 
-pub fn insert_event(table: &Table, conn: &GenericConnection, json: &serde_json::Value, headers: &HeaderMap) -> Result<(), DbError> {
-    let query = format!(r#"INSERT INTO "{}" ({}) VALUES ({})"#,
-                        table.name,
-                        table.columns.iter().map(|column| format!(r#""{}""#, column.name)).join(", "),
-                        (1..=table.columns.len()).map(|idx| format!("${}", idx)).join(", "));
-    let mut values = Vec::<Box<ToSql>>::with_capacity(table.columns.len());
+/// Connection pools for the default database, and for each `database_url` that apps have
+/// configured.
+#[derive(Clone, Debug)]
+pub struct Pools {
+    pub default: Pool<PostgresConnectionManager>,
+    pub by_url: HashMap<String, Pool<PostgresConnectionManager>>,
+}
+
+impl Pools {
+    /// Returns the pool for the database that the app's events are stored in.
+    pub fn for_app(&self, app: &App) -> &Pool<PostgresConnectionManager> {
+        app.database_url.as_ref()
+            .and_then(|database_url| self.by_url.get(database_url))
+            .unwrap_or(&self.default)
+    }
+
+    /// Returns all pools, along with the `database_url` they are for; `None` for the default
+    /// database.
+    pub fn all(&self) -> impl Iterator<Item = (Option<&str>, &Pool<PostgresConnectionManager>)> {
+        iter::once((None, &self.default))
+            .chain(self.by_url.iter().map(|(database_url, pool)| (Some(database_url.as_str()), pool)))
+    }
+}
+
+/// Sets the Postgres `statement_timeout` on every connection that a pool opens, as given by
+/// `--statement-timeout`, so that a statement that hangs on a lock or a bad plan fails instead of
+/// holding on to the connection indefinitely.
+#[derive(Debug)]
+pub struct StatementTimeout(pub Duration);
+
+impl r2d2::CustomizeConnection<postgres::Connection, postgres::Error> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut postgres::Connection) -> Result<(), postgres::Error> {
+        conn.batch_execute(&statement_timeout_query(self.0))
+    }
+}
+
+fn statement_timeout_query(timeout: Duration) -> String {
+    format!("SET statement_timeout = {}", timeout.as_millis())
+}
+
+/// Name of the column that is added to tables that have `id_column` set.
+pub const ID_COLUMN_NAME: &str = "id";
+
+/// The parts of the HTTP request, other than the body, that columns can take their values from.
+#[derive(Debug)]
+pub struct RequestMetadata<'a> {
+    pub method: &'a str,
+    pub path: &'a str,
+    pub headers: &'a HeaderMap<'a>,
+    pub received_at: DateTime<Utc>,
+    pub client_ip: Option<IpAddr>,
+    pub geoip: Option<&'a GeoIp>,
+    pub encryption: Option<&'a Encryption>,
+    /// Size of the request body in bytes, once it has been read.
+    pub body_size: Option<u64>,
+    /// UUID that is shared by all events in the same request, once the request is handled.
+    pub batch_id: Option<&'a str>,
+    /// The query string of the request URL, still percent-encoded.
+    pub query: Option<&'a str>,
+}
+
+/// Returns the decoded value of the first parameter with the given name in a query string.
+fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query.map(FormItems::from).into_iter().flatten()
+        .map(|item| item.key_value_decoded())
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value)
+}
+
+/// Field in which `apply_default_table` keeps the original value of the event's `_t` field.
+pub const ORIGINAL_TABLE_FIELD: &str = "_original_t";
+
+/// Returns the table name that the client gave in the event's `_t` field, before it was replaced
+/// by the app's `default_table`. Multiple names are separated by commas.
+fn event_type(event: &serde_json::Value) -> Option<String> {
+    match event.get(ORIGINAL_TABLE_FIELD).or_else(|| event.get("_t"))? {
+        serde_json::Value::String(table_name) => Some(table_name.to_string()),
+        serde_json::Value::Array(table_names) => table_names.iter()
+            .map(serde_json::Value::as_str)
+            .collect::<Option<Vec<_>>>()
+            .map(|table_names| table_names.join(",")),
+        _ => None,
+    }
+}
+
+/// Returns the names of the tables that an event should be inserted into, as given by its `_t`
+/// field. This is either a single table name, or an array of them to insert the same event into
+/// multiple tables. Returns `None` if the field is missing or malformed.
+pub fn table_names(event: &serde_json::Value) -> Option<Vec<&str>> {
+    match &event["_t"] {
+        serde_json::Value::String(table_name) => Some(vec![table_name]),
+        serde_json::Value::Array(table_names) if !table_names.is_empty() =>
+            table_names.iter().map(serde_json::Value::as_str).collect(),
+        _ => None,
+    }
+}
+
+/// An event that has been checked against the schema and converted to the values of the columns
+/// of each table it goes into, as given by its `_t` field. Preparing all events before starting a
+/// transaction means that invalid events are reported, with the offending field, without doing
+/// any database work.
+#[derive(Debug)]
+pub struct PreparedEvent<'a> {
+    pub event: &'a serde_json::Value,
+    rows: Vec<(&'a Table, Vec<Box<ToSql + 'a>>)>,
+}
+
+impl<'a> PreparedEvent<'a> {
+    /// Prepares an event for insertion. The `table_prefix` of the app that sent the event is
+    /// prepended to the table names.
+    pub fn new(schema: &'a Schema, table_prefix: &str, event: &'a serde_json::Value, metadata: &'a RequestMetadata) -> Result<PreparedEvent<'a>, DbError> {
+        let table_names = table_names(event)
+            .ok_or_else(|| DbError::StructureError("event has no table name".to_string()))?;
+        let mut rows = Vec::with_capacity(table_names.len());
+        for table_name in table_names {
+            let table = schema.tables.get(&format!("{}{}", table_prefix, table_name))
+                .ok_or_else(|| DbError::StructureError(format!("table \"{}{}\" does not exist", table_prefix, table_name)))?;
+            rows.push((table, event_values(table, event, metadata)?));
+        }
+        Ok(PreparedEvent { event, rows })
+    }
+
+    /// Returns whether a row was written to any of the tables.
+    fn insert(&self, conn: &GenericConnection) -> Result<bool, DbError> {
+        let mut written = false;
+        for (table, values) in &self.rows {
+            let _span = tracing::info_span!("insert", table = %table.name, events = 1).entered();
+            written |= insert_row(table, conn, values)?;
+        }
+        Ok(written)
+    }
+}
+
+/// Returns whether a row was written, which is not the case if the event was skipped because of
+/// `on_conflict: ignore`.
+fn insert_row(table: &Table, conn: &GenericConnection, values: &[Box<ToSql + '_>]) -> Result<bool, DbError> {
+    trace!(query = %table.insert_query, ?values, "inserting event");
+    // The statement is prepared once per connection and reused afterwards.
+    let inserted = conn.prepare_cached(&table.insert_query)
+        .and_then(|statement| statement.execute(&values.iter().map(|v| v.as_ref()).collect::<Vec<&ToSql>>()))
+        .map_err(|err| {
+            warn!(table = %table.name, error = %err, "failed to insert event");
+            err
+        })?;
+    // Events that were skipped because of on_conflict: ignore are not counted either. Rollups can't be
+    // combined with on_conflict: update, whose updated rows would be counted again.
+    if inserted > 0 {
+        for rollup in &table.rollups {
+            update_rollup(table, rollup, conn, values)?;
+        }
+    }
+    Ok(inserted > 0)
+}
+
+/// Adds an inserted event, given by the values of the table's columns, to the count of its bucket
+/// in the rollup table.
+fn update_rollup(table: &Table, rollup: &Rollup, conn: &GenericConnection, values: &[Box<ToSql + '_>]) -> Result<(), DbError> {
+    let value = |column_name: &str| table.columns.iter()
+        .position(|column| column.name == column_name)
+        .map(|idx| values[idx].as_ref())
+        .ok_or_else(|| DbError::StructureError(format!("rollup \"{}\" uses column \"{}\", which does not exist", rollup.name, column_name)));
+    let parameters = iter::once(&rollup.time_column).chain(&rollup.group_by)
+        .map(|column_name| value(column_name))
+        .collect::<Result<Vec<&ToSql>, DbError>>()?;
+    conn.prepare_cached(&rollup.upsert_query)
+        .and_then(|statement| statement.execute(&parameters))
+        .map_err(|err| {
+            warn!(table = %table.name, rollup = %rollup.name, error = %err, "failed to update rollup");
+            err
+        })?;
+    Ok(())
+}
+
+/// Returns the query that adds one event to the count of its bucket in a rollup of the table,
+/// taking the values of the `time_column` and the `group_by` columns as parameters, in order.
+/// Buckets are computed in UTC, regardless of the time zone of the database session. This is
+/// computed once when the schema is loaded and stored in `Rollup::upsert_query`.
+pub fn rollup_upsert_query(table: &Table, rollup: &Rollup) -> String {
+    let group_parameters = rollup.group_by.iter().enumerate()
+        .map(|(idx, column_name)| table.columns.iter()
+            .find(|column| &column.name == column_name)
+            .map_or(Type::default(), |column| column.type_.clone())
+            .parameter_expression(idx + 2));
+    let key_columns = iter::once(ROLLUP_BUCKET_COLUMN).chain(rollup.group_by.iter().map(String::as_str))
+        .map(|column_name| format!(r#""{}""#, column_name))
+        .join(", ");
+    format!(r#"INSERT INTO "{}" ({}, "{}") VALUES ({}, 1) ON CONFLICT ({}) DO UPDATE SET "{}" = "{}"."{}" + 1"#,
+            rollup.name,
+            key_columns,
+            ROLLUP_COUNT_COLUMN,
+            iter::once(format!("date_trunc('{}', $1::TIMESTAMPTZ AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'", rollup.bucket.date_trunc_field()))
+                .chain(group_parameters)
+                .join(", "),
+            key_columns,
+            ROLLUP_COUNT_COLUMN, rollup.name, ROLLUP_COUNT_COLUMN)
+}
+
+/// Inserts a batch of prepared events in a single transaction. Each table only receives the
+/// columns it declares. The events are inserted table by table, in order within each table.
+/// Returns whether each event was written to any table, rather than skipped as a duplicate.
+pub fn insert_events(conn: &GenericConnection, events: &[PreparedEvent]) -> Result<Vec<bool>, DbError> {
+    let trans = conn.transaction()?;
+    create_partitions(&trans, events)?;
+    let rows_by_table = events.iter().enumerate()
+        .flat_map(|(index, event)| event.rows.iter().map(move |row| (index, row)))
+        .map(|(index, (table, values))| (&table.name, (index, *table, values)))
+        .into_group_map();
+    let mut written = vec![false; events.len()];
+    for (table_name, rows) in rows_by_table.iter().sorted_by_key(|(table_name, _)| *table_name) {
+        let _span = tracing::info_span!("insert", table = %table_name, events = rows.len()).entered();
+        for &(index, table, values) in rows {
+            written[index] |= insert_row(table, &trans, values)?;
+        }
+    }
+    trans.commit()?;
+    Ok(written)
+}
+
+/// Like `insert_events`, but each event is inserted independently of the others, so that events
+/// that the database rejects don't prevent others from being inserted. Returns the result for each
+/// event. Errors that affect the entire batch, such as a lost database connection, are returned as
+/// a whole.
+pub fn insert_events_partially(conn: &GenericConnection, events: &[PreparedEvent]) -> Result<Vec<Result<bool, DbError>>, DbError> {
+    let trans = conn.transaction()?;
+    create_partitions(&trans, events)?;
+    let mut results = Vec::with_capacity(events.len());
+    for event in events {
+        // A failed statement aborts the entire transaction, unless we roll back to a savepoint.
+        let savepoint = trans.savepoint("event")?;
+        match event.insert(&savepoint) {
+            Ok(written) => {
+                savepoint.commit()?;
+                results.push(Ok(written));
+            }
+            Err(err) if err.is_connection_error() => return Err(err),
+            Err(err) => results.push(Err(err)),
+        }
+    }
+    trans.commit()?;
+    Ok(results)
+}
+
+/// Returns the query that inserts an event into the table, taking the values of the table's
+/// columns as parameters, in order. This is computed once when the schema is loaded and stored in
+/// `Table::insert_query`.
+pub fn insertion_query(table: &Table) -> String {
+    let updated_columns = table.columns.iter()
+        .filter(|column| !table.conflict_key().contains(&column.name))
+        .map(|column| format!(r#""{}" = EXCLUDED."{}""#, column.name, column.name))
+        .join(", ");
+    let on_conflict = match table.on_conflict {
+        OnConflict::Error => "".to_string(),
+        OnConflict::Ignore => " ON CONFLICT DO NOTHING".to_string(),
+        // If every column is part of the key, there is nothing to update.
+        OnConflict::Update if updated_columns.is_empty() => " ON CONFLICT DO NOTHING".to_string(),
+        OnConflict::Update => format!(" ON CONFLICT ({}) DO UPDATE SET {}",
+            table.conflict_key().iter().map(|column_name| format!(r#""{}""#, column_name)).join(", "),
+            updated_columns),
+    };
+    format!(r#"INSERT INTO "{}" ({}) VALUES ({}){}"#,
+            table.name,
+            table.columns.iter().map(|column| format!(r#""{}""#, column.name)).join(", "),
+            table.columns.iter().enumerate().map(|(idx, column)| column.type_.parameter_expression(idx + 1)).join(", "),
+            on_conflict)
+}
+
+/// Returns the values of the table's columns for the given event, in the order expected by
+/// `Table::insert_query`. Columns with a `header` or `source` take their value from the request
+/// metadata instead of the event, as do columns with `fill_if_absent` if the event has no value.
+fn event_values<'a>(table: &'a Table, json: &'a serde_json::Value, metadata: &'a RequestMetadata) -> Result<Vec<Box<ToSql + 'a>>, DbError> {
+    let mut values = Vec::<Box<ToSql + 'a>>::with_capacity(table.columns.len());
     for column in &table.columns {
-        let value = match &column.header {
-            Some(header) => header_to_sql(&column.name, headers.get(&header).next(), column.required),
-            None => column.type_.json_to_sql(&column.name, &json[&column.name], column.required),
+        let required = is_required(table, column, json);
+        let value = match (&column.header, &column.source) {
+            (Some(header), _) => header_to_sql(&column.name, metadata.headers.get(&header).next(), required),
+            (None, Some(Source::RequestMethod)) => header_to_sql(&column.name, Some(metadata.method), required),
+            (None, Some(Source::RequestPath)) => header_to_sql(&column.name, Some(metadata.path), required),
+            (None, Some(Source::ReceivedAt)) => unwrap_if_required(&column.name, Some(metadata.received_at), required),
+            (None, Some(Source::GeoipCountry)) => {
+                let country = match (metadata.geoip, metadata.client_ip) {
+                    (Some(geoip), Some(client_ip)) => geoip.country(client_ip),
+                    _ => None,
+                };
+                unwrap_if_required(&column.name, country, required)
+            }
+            (None, Some(Source::BodySize)) =>
+                unwrap_if_required(&column.name, metadata.body_size.map(|size| size as i64), required),
+            (None, Some(Source::BatchId)) => header_to_sql(&column.name, metadata.batch_id, required),
+            (None, Some(Source::EventType)) => unwrap_if_required(&column.name, event_type(json), required),
+            (None, Some(Source::Query(param))) => unwrap_if_required(&column.name, query_param(metadata.query, param), required),
+            (None, Some(Source::RawEvent)) if column.type_ == Type::String =>
+                unwrap_if_required(&column.name, Some(json.to_string()), required),
+            (None, Some(Source::RawEvent)) => column.type_.json_to_sql(&column.name, json, required, column.allow_non_finite),
+            (None, None) if column.encrypt => match metadata.encryption {
+                Some(encryption) => {
+                    let value = column_value(column, json);
+                    unwrap_if_required(&column.name, value.as_str().map(|s| encryption.encrypt(s)), required)
+                }
+                None => return Err(DbError::StructureError(format!("column \"{}\" is encrypted, but no encryption key was given", column.name))),
+            },
+            (None, None) if column.fill_if_absent == Some(FillIfAbsent::Now) && column_value(column, json).is_null() =>
+                unwrap_if_required(&column.name, Some(metadata.received_at), required),
+            (None, None) => {
+                let value = column_value(column, json);
+                let json = &*value;
+                match &column.allowed_values {
+                    Some(allowed_values) if !json.is_null() && !is_allowed(allowed_values, json) =>
+                        Err(ConversionError::NotAllowed(column.name.to_string())),
+                    _ if column.reject_precision_loss => check_f32_precision(&column.name, json)
+                        .and_then(|()| column.type_.json_to_sql(&column.name, json, required, column.allow_non_finite)),
+                    _ => column.type_.json_to_sql(&column.name, json, required, column.allow_non_finite),
+                }
+            }
         }.map_err(|err| DbError::ConversionError(column.name.to_string(), err))?;
         values.push(value);
     }
-    // println!("{} {:?}", query, values);
-    conn.execute(&query, &values.iter().map(|v| v.as_ref()).collect::<Vec<&ToSql>>())?;
-    Ok(())
+    Ok(values)
+}
+
+/// Returns the value of a column that is populated from the event: the event's value for its key,
+/// or the column's default if that is missing or null, with `trim` and `lowercase` applied.
+fn column_value<'a>(column: &'a Column, json: &'a serde_json::Value) -> Cow<'a, serde_json::Value> {
+    let json = match (lookup(json, column.json_key()), &column.default) {
+        (serde_json::Value::Null, Some(default)) => default,
+        (json, _) => json,
+    };
+    column.normalize(json)
 }
 
+/// Returns whether the column must have a value in the given event: either it is `required`, or
+/// the other column named in its `required_if` has the given value.
+fn is_required(table: &Table, column: &Column, json: &serde_json::Value) -> bool {
+    column.required || column.required_if.as_ref().map_or(false, |required_if| {
+        table.columns.iter()
+            .find(|other| other.name == required_if.column)
+            .map_or(false, |other| is_allowed(slice::from_ref(&required_if.equals), &column_value(other, json)))
+    })
+}
+
+static NULL: serde_json::Value = serde_json::Value::Null;
+
+/// Looks up the value for the given column key in an event. If the event has a key that is
+/// exactly equal to it, that is used. Otherwise, dots in the key separate the keys of
+/// nested objects, so `device.os` refers to `{"device": {"os": ...}}`. A literal dot in one of
+/// those keys is written as `\.`, and a literal backslash as `\\`. Returns null if there is no
+/// such value.
+fn lookup<'a>(json: &'a serde_json::Value, dotted_key: &str) -> &'a serde_json::Value {
+    if let Some(value) = json.get(dotted_key) {
+        return value;
+    }
+    let mut value = json;
+    for key in split_dotted_key(dotted_key) {
+        value = match value.get(&key) {
+            Some(value) => value,
+            None => return &NULL,
+        };
+    }
+    value
+}
+
+fn split_dotted_key(dotted_key: &str) -> Vec<String> {
+    let mut keys = vec![String::new()];
+    let mut chars = dotted_key.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => keys.push(String::new()),
+            '\\' => keys.last_mut().unwrap().push(chars.next().unwrap_or('\\')),
+            c => keys.last_mut().unwrap().push(c),
+        }
+    }
+    keys
+}
+
+/// Returns the top-level keys of the event that none of the given tables read a column from.
+/// Keys starting with `_`, such as the table name, are metadata and never unknown. If any of the
+/// tables stores the entire event, no keys are unknown.
+pub fn unknown_keys<'a>(tables: &[&Table], event: &'a serde_json::Value) -> Vec<&'a str> {
+    if tables.iter().flat_map(|table| &table.columns).any(|column| column.source == Some(Source::RawEvent)) {
+        return Vec::new();
+    }
+    let known_keys = tables.iter()
+        .flat_map(|table| table.columns.iter())
+        .filter(|column| column.header.is_none() && column.source.is_none())
+        .flat_map(|column| vec![column.json_key().to_string(), split_dotted_key(column.json_key()).swap_remove(0)])
+        .collect::<HashSet<_>>();
+    event.as_object().into_iter()
+        .flat_map(|object| object.keys())
+        .filter(|key| !key.starts_with('_') && !known_keys.contains(*key))
+        .map(String::as_str)
+        .collect()
+}
+
+/// Creates the enum types and tables of the schema that don't exist yet, and checks the existing
+/// ones against it. This happens in a single transaction, so it either succeeds completely or
+/// changes nothing.
 pub fn create_tables(schema: &Schema, conn: &GenericConnection) -> Result<(), DbError> {
-    let existing_tables = conn.query(r#"
+    let trans = conn.transaction()?;
+    let conn: &GenericConnection = &trans;
+    for (enum_name, values) in schema.enums.iter().sorted() {
+        match existing_enum_values(enum_name, conn)? {
+            None => {
+                conn.execute(&enum_creation_query(enum_name, values), &[])?;
+            }
+            Some(existing_values) => check_enum(enum_name, values, &existing_values)?,
+        }
+    }
+    let existing_tables = existing_tables(conn)?;
+    for table in tables_and_rollups(schema) {
+        if !existing_tables.contains(&table.name) {
+            conn.execute(&creation_query(&table), &[])?;
+            for query in constraint_comment_queries(&table) {
+                conn.execute(&query, &[])?;
+            }
+        } else {
+            check_table(&table, conn)?;
+        }
+    }
+    trans.commit()?;
+    Ok(())
+}
+
+/// Returns the SQL statements that `create_tables` would execute on an empty database, separated
+/// by newlines, so they can be reviewed or applied by hand.
+pub fn schema_sql(schema: &Schema) -> String {
+    let enums = schema.enums.iter().sorted()
+        .map(|(enum_name, values)| enum_creation_query(enum_name, values));
+    let tables = tables_and_rollups(schema).into_iter().sorted_by(|a, b| a.name.cmp(&b.name))
+        .flat_map(|table| iter::once(creation_query(&table)).chain(constraint_comment_queries(&table)));
+    enums.chain(tables)
+        .map(|query| format!("{};\n", query.trim()))
+        .collect()
+}
+
+/// Returns the tables of the schema, followed by the tables that hold their rollups.
+fn tables_and_rollups(schema: &Schema) -> Vec<Cow<Table>> {
+    let rollup_tables = schema.tables.values().flat_map(Table::rollup_tables).map(Cow::Owned);
+    schema.tables.values().map(Cow::Borrowed).chain(rollup_tables).collect()
+}
+
+fn enum_creation_query(enum_name: &str, values: &[String]) -> String {
+    format!(r#"CREATE TYPE "{}" AS ENUM ({})"#,
+            enum_name, values.iter().map(|value| json_to_sql_literal(&serde_json::Value::String(value.to_string()))).join(", "))
+}
+
+/// Returns the queries that record the allowed values of the table's columns in a comment on their
+/// constraint, so that `check_table` can compare them without having to parse the constraint
+/// expression.
+fn constraint_comment_queries(table: &Table) -> Vec<String> {
+    table.columns.iter()
+        .filter_map(|column| column.allowed_values.as_ref().map(|allowed_values| format!(
+            r#"COMMENT ON CONSTRAINT "{}" ON "{}" IS {}"#,
+            check_constraint_name(table, column), table.name,
+            json_to_sql_literal(&serde_json::Value::String(serde_json::to_string(allowed_values).unwrap())))))
+        .collect()
+}
+
+/// Checks all existing enum types and tables against the schema, without modifying anything.
+/// Returns the mismatches found, at most one per enum or table. Tables that don't exist yet are
+/// not an error, because they would be created by `create_tables`.
+pub fn check_tables(schema: &Schema, conn: &GenericConnection) -> Result<Vec<DbError>, DbError> {
+    let mut errors = Vec::new();
+    for (enum_name, values) in schema.enums.iter().sorted() {
+        if let Some(existing_values) = existing_enum_values(enum_name, conn)? {
+            if let Err(err) = check_enum(enum_name, values, &existing_values) {
+                errors.push(err);
+            }
+        }
+    }
+    let existing_tables = existing_tables(conn)?;
+    for table in tables_and_rollups(schema).into_iter().sorted_by(|a, b| a.name.cmp(&b.name)) {
+        if existing_tables.contains(&table.name) {
+            match check_table(&table, conn) {
+                Ok(()) => {}
+                Err(err @ DbError::StructureError(_)) => errors.push(err),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    Ok(errors)
+}
+
+/// Returns the values of the enum type with the given name, in order, or `None` if there is no
+/// such type.
+fn existing_enum_values(enum_name: &str, conn: &GenericConnection) -> Result<Option<Vec<String>>, DbError> {
+    let exists = !conn.query(r#"
+        SELECT 1
+        FROM pg_catalog.pg_type
+        WHERE typname = $1 AND typtype = 'e' AND pg_catalog.pg_type_is_visible(oid)
+        "#, &[&enum_name])?.is_empty();
+    if !exists {
+        return Ok(None);
+    }
+    Ok(Some(conn.query(r#"
+        SELECT e.enumlabel::TEXT
+        FROM pg_catalog.pg_enum e
+            JOIN pg_catalog.pg_type t ON t.oid = e.enumtypid
+        WHERE t.typname = $1 AND pg_catalog.pg_type_is_visible(t.oid)
+        ORDER BY e.enumsortorder
+        "#, &[&enum_name])?
+        .iter()
+        .map(|row| row.get(0))
+        .collect()))
+}
+
+fn check_enum(enum_name: &str, values: &[String], existing_values: &[String]) -> Result<(), DbError> {
+    if values != existing_values {
+        return Err(DbError::StructureError(format!(
+            "enum \"{}\" has values {:?}, which do not match {:?} configured in the schema",
+            enum_name, existing_values, values)));
+    }
+    Ok(())
+}
+
+fn existing_tables(conn: &GenericConnection) -> Result<HashSet<String>, DbError> {
+    Ok(conn.query(r#"
         SELECT relname
         FROM pg_catalog.pg_class
         WHERE pg_catalog.pg_table_is_visible(oid)
         "#, &[])?
         .iter()
         .map(|row| row.get(0))
-        .collect::<HashSet<String>>();
+        .collect::<HashSet<String>>())
+}
 
-    for table in schema.tables.values() {
-        if !existing_tables.contains(&table.name) {
-            conn.execute(&creation_query(table), &[])?;
-        } else {
-            check_table(&table, conn)?;
+/// Creates the monthly partitions needed to insert the given events into partitioned tables, if
+/// they don't exist yet.
+fn create_partitions(conn: &GenericConnection, events: &[PreparedEvent]) -> Result<(), DbError> {
+    let mut partitions = HashSet::new();
+    for PreparedEvent { event, rows } in events {
+        for (table, _) in rows {
+            let partition_column = table.partition_by.as_ref()
+                .and_then(|partition_by| table.columns.iter().find(|column| &column.name == partition_by));
+            if let Some(partition_column) = partition_column {
+                // Missing or invalid values have been rejected when the event was prepared.
+                if let Ok(Some(time)) = json_to_date_time(lookup(event, partition_column.json_key())) {
+                    let time = time.with_timezone(&Utc);
+                    partitions.insert((&table.name, time.year(), time.month()));
+                }
+            }
+        }
+    }
+    for (table_name, year, month) in partitions {
+        let partition_name = format!("{}_{:04}_{:02}", table_name, year, month);
+        let exists = !conn.query(r#"
+            SELECT relname
+            FROM pg_catalog.pg_class
+            WHERE relname = $1 AND pg_catalog.pg_table_is_visible(oid)
+            "#, &[&partition_name])?.is_empty();
+        if !exists {
+            conn.execute(&partition_creation_query(table_name, &partition_name, year, month), &[])?;
         }
     }
     Ok(())
 }
 
+fn partition_creation_query(table_name: &str, partition_name: &str, year: i32, month: u32) -> String {
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    format!(r#"
+        CREATE TABLE IF NOT EXISTS "{}" PARTITION OF "{}"
+        FOR VALUES FROM ('{:04}-{:02}-01 00:00:00+00') TO ('{:04}-{:02}-01 00:00:00+00')
+        "#, partition_name, table_name, year, month, next_year, next_month)
+}
+
+/// Drops the monthly partitions of the table that only contain events from before `cutoff`.
+/// Returns the names of the dropped partitions.
+pub fn drop_expired_partitions(table: &Table, conn: &GenericConnection, cutoff: DateTime<Utc>) -> Result<Vec<String>, DbError> {
+    let partitions = conn.query(r#"
+        SELECT c.relname
+        FROM pg_catalog.pg_inherits i
+            JOIN pg_catalog.pg_class c ON c.oid = i.inhrelid
+        WHERE i.inhparent = (
+            SELECT oid
+            FROM pg_catalog.pg_class
+            WHERE relname = $1 AND pg_catalog.pg_table_is_visible(oid)
+        )
+        "#, &[&table.name])?
+        .iter()
+        .map(|row| row.get(0))
+        .collect::<Vec<String>>();
+    let mut dropped = Vec::new();
+    for partition_name in partitions {
+        let end = match partition_end(&table.name, &partition_name) {
+            Some(end) => end,
+            None => continue, // Not created by us.
+        };
+        if end <= cutoff {
+            conn.execute(&format!(r#"DROP TABLE "{}""#, partition_name), &[])?;
+            dropped.push(partition_name);
+        }
+    }
+    Ok(dropped)
+}
+
+/// Returns the end of the month covered by a partition created by `create_partitions`.
+fn partition_end(table_name: &str, partition_name: &str) -> Option<DateTime<Utc>> {
+    // The name comes from the database, so it may contain anything, including multibyte characters.
+    let suffix = partition_name.strip_prefix(table_name)?;
+    if suffix.len() != 8 || suffix.get(0..1)? != "_" || suffix.get(5..6)? != "_" {
+        return None;
+    }
+    let year = suffix.get(1..5)?.parse::<i32>().ok()?;
+    let month = suffix.get(6..8)?.parse::<u32>().ok()?;
+    let (next_year, next_month) = if month == 12 { (year + 1, 1) } else { (year, month + 1) };
+    Some(Utc.ymd_opt(next_year, next_month, 1).single()?.and_hms(0, 0, 0))
+}
+
+/// Deletes events whose value in the given timestamp column is before `cutoff`. Returns the
+/// number of deleted rows.
+pub fn delete_expired_events(table: &Table, column_name: &str, conn: &GenericConnection, cutoff: DateTime<Utc>) -> Result<u64, DbError> {
+    Ok(conn.execute(&format!(r#"DELETE FROM "{}" WHERE "{}" < $1"#, table.name, column_name), &[&cutoff])?)
+}
+
+/// Deletes the events whose columns are equal to all of the given values, e.g. to erase the data
+/// of a single user. Only indexed columns may be used, so that this doesn't scan the entire table.
+/// Returns the number of deleted rows.
+pub fn delete_matching_events(table: &Table, conn: &GenericConnection, filters: &[(String, String)]) -> Result<u64, DbError> {
+    let (query, values) = deletion_query(table, filters)?;
+    Ok(conn.execute(&query, &values.iter().map(|v| v.as_ref()).collect::<Vec<&ToSql>>())?)
+}
+
+/// Returns the statement for `delete_matching_events`, along with its parameters.
+fn deletion_query(table: &Table, filters: &[(String, String)]) -> Result<(String, Vec<Box<ToSql>>), DbError> {
+    // Without any filters, this would delete everything.
+    if filters.is_empty() {
+        return Err(DbError::StructureError("no columns to match were given".to_string()));
+    }
+    let mut conditions = Vec::with_capacity(filters.len());
+    let mut values = Vec::<Box<ToSql>>::with_capacity(filters.len());
+    for (column_name, value) in filters {
+        let column = table.columns.iter().find(|column| &column.name == column_name)
+            .ok_or_else(|| DbError::StructureError(format!("table \"{}\" has no column \"{}\"", table.name, column_name)))?;
+        if !column.indexed {
+            return Err(DbError::StructureError(format!("column \"{}\" is not indexed", column_name)));
+        }
+        let json = match (&column.type_, value.parse::<bool>()) {
+            (Type::Bool, Ok(value)) => serde_json::Value::Bool(value),
+            _ => serde_json::Value::String(value.to_string()),
+        };
+        values.push(column.type_.json_to_sql(&column.name, &json, true, column.allow_non_finite)
+            .map_err(|err| DbError::ConversionError(column.name.to_string(), err))?);
+        conditions.push(format!(r#""{}" = {}"#, column.name, column.type_.parameter_expression(values.len())));
+    }
+    let query = format!(r#"DELETE FROM "{}" WHERE {}"#, table.name, conditions.join(" AND "));
+    Ok((query, values))
+}
+
+/// An event as read back from the database, keyed by column name.
+pub type StoredEvent = serde_json::Map<String, serde_json::Value>;
+
+/// Returns at most `limit` events with an `id` greater than `after`, in order of `id`, along with
+/// their `id`. The table must have `id_column` set. Encrypted columns are decrypted with
+/// `encryption`; they are null if it is not given or the value can't be decrypted.
+pub fn select_events(table: &Table, conn: &GenericConnection, after: i64, limit: i64, encryption: Option<&Encryption>)
+    -> Result<Vec<(i64, StoredEvent)>, DbError>
+{
+    let query = format!(r#"SELECT "{}", {} FROM "{}" WHERE "{}" > $1 ORDER BY "{}" LIMIT $2"#,
+                        ID_COLUMN_NAME,
+                        table.columns.iter().map(|column| column.type_.select_expression(&column.name)).join(", "),
+                        table.name, ID_COLUMN_NAME, ID_COLUMN_NAME);
+    Ok(conn.query(&query, &[&after, &limit])?
+        .iter()
+        .map(|row| {
+            let id: i64 = row.get(0);
+            let mut event = StoredEvent::new();
+            event.insert(ID_COLUMN_NAME.to_string(), id.into());
+            for (idx, column) in table.columns.iter().enumerate() {
+                let value = if column.encrypt {
+                    serde_json::json!(row.get::<_, Option<Vec<u8>>>(idx + 1)
+                        .and_then(|data| encryption.and_then(|encryption| encryption.decrypt(&data))))
+                } else {
+                    column.type_.row_to_json(&row, idx + 1)
+                };
+                event.insert(column.name.to_string(), value);
+            }
+            (id, event)
+        })
+        .collect())
+}
+
 fn creation_query(table: &Table) -> String {
     let columns = table.columns
         .iter()
         .map(|column| format!(
-            r#"{} {}{}"#,
+            r#""{}" {}{}{}"#,
             column.name,
-            column.type_.postgres_type_name(),
+            column.postgres_type_name(),
+            column.default.as_ref().map_or("".to_string(), |default| format!(" DEFAULT {}", json_to_sql_literal(default))),
             if column.required { " not null" } else { "" }
         ))
         .join(", ");
+    let constraints = table.columns
+        .iter()
+        .filter_map(|column| column.allowed_values.as_ref().map(|allowed_values| format!(
+            r#", CONSTRAINT "{}" CHECK ("{}" IN ({}))"#,
+            check_constraint_name(table, column),
+            column.name,
+            allowed_values.iter().map(json_to_sql_literal).join(", ")
+        )))
+        .join("");
+    // On a partitioned table, the primary key must include the partitioning column.
+    let (id_column, primary_key) = if table.id_column {
+        let key_columns = match &table.partition_by {
+            Some(partition_by) => format!(r#""{}", "{}""#, ID_COLUMN_NAME, partition_by),
+            None => format!(r#""{}""#, ID_COLUMN_NAME),
+        };
+        (format!(r#""{}" BIGSERIAL, "#, ID_COLUMN_NAME), format!(", PRIMARY KEY ({})", key_columns))
+    } else if !table.primary_key.is_empty() {
+        ("".to_string(), format!(", PRIMARY KEY ({})", table.primary_key.iter().map(|column_name| format!(r#""{}""#, column_name)).join(", ")))
+    } else {
+        ("".to_string(), "".to_string())
+    };
+    let unique_key = if table.unique_key.is_empty() {
+        "".to_string()
+    } else {
+        format!(", UNIQUE ({})", table.unique_key.iter().map(|column_name| format!(r#""{}""#, column_name)).join(", "))
+    };
+    let partitioning = match &table.partition_by {
+        Some(partition_by) => format!(r#" PARTITION BY RANGE ("{}")"#, partition_by),
+        None => "".to_string(),
+    };
     format!(r#"
-        CREATE TABLE "{}" ({})
-        "#, table.name, columns)
+        CREATE TABLE "{}" ({}{}{}{}{}){}
+        "#, table.name, id_column, columns, primary_key, unique_key, constraints, partitioning)
+}
+
+/// Returns whether the table has a unique index on exactly the given columns, in any order. If
+/// `primary_key` is true, the index must be the table's primary key.
+fn has_unique_index(conn: &GenericConnection, table_name: &str, columns: &[String], primary_key: bool) -> Result<bool, DbError> {
+    let mut columns = columns.to_vec();
+    columns.sort();
+    Ok(conn.query(r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM
+                pg_catalog.pg_index i
+                JOIN pg_catalog.pg_class c ON c.oid = i.indrelid
+            WHERE
+                i.indisunique
+                AND (i.indisprimary OR NOT $3)
+                AND c.relname = $1
+                AND pg_catalog.pg_table_is_visible(c.oid)
+                AND ARRAY(
+                    SELECT a.attname::text
+                    FROM
+                        unnest(i.indkey::int2[]) k
+                        JOIN pg_catalog.pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = k
+                    ORDER BY a.attname
+                ) = $2
+        )
+        "#, &[&table_name, &columns, &primary_key])?.get(0).get(0))
+}
+
+/// Returns whether the default expression of an existing column, as returned by `pg_get_expr`,
+/// matches the default configured for the column. Postgres normalizes the expression, e.g. `'a'`
+/// becomes `'a'::character varying`, so rather than comparing text, both are evaluated.
+fn default_matches(column: &Column, existing_default: Option<&str>, conn: &GenericConnection) -> Result<bool, DbError> {
+    match (&column.default, existing_default) {
+        (None, None) => Ok(true),
+        (Some(default), Some(existing_default)) => {
+            let type_name = column.type_.postgres_type_name();
+            Ok(conn.query(&format!(
+                "SELECT ({})::{} IS NOT DISTINCT FROM ({})::{}",
+                existing_default, type_name, json_to_sql_literal(default), type_name), &[])?
+                .get(0)
+                .get(0))
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Returns the name of the `CHECK` constraint that enforces the `allowed_values` of a column.
+pub fn check_constraint_name(table: &Table, column: &Column) -> String {
+    format!("{}_{}_allowed_values", table.name, column.name)
 }
 
 fn check_table(table: &Table, conn: &GenericConnection) -> Result<(), DbError> {
@@ -93,10 +883,13 @@ fn check_table(table: &Table, conn: &GenericConnection) -> Result<(), DbError> {
         SELECT
             a.attname as "name",
             a.atttypid as "type_oid",
+            a.atttypmod as "type_modifier",
             pg_catalog.format_type(a.atttypid, a.atttypmod) as "postgres_type",
-            a.attnotnull and not a.atthasdef as "required"
+            a.attnotnull and not a.atthasdef as "required",
+            pg_catalog.pg_get_expr(d.adbin, d.adrelid) as "default"
         FROM
             pg_catalog.pg_attribute a
+            LEFT JOIN pg_catalog.pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
         WHERE
             a.attnum > 0
             AND NOT a.attisdropped
@@ -111,22 +904,37 @@ fn check_table(table: &Table, conn: &GenericConnection) -> Result<(), DbError> {
     for existing_column in &existing_columns {
         let name: String = existing_column.get("name");
         let type_oid: postgres::types::Oid = existing_column.get("type_oid");
+        let type_modifier: i32 = existing_column.get("type_modifier");
         let postgres_type: String = existing_column.get("postgres_type");
         let required: bool = existing_column.get("required");
+        let default: Option<String> = existing_column.get("default");
 
         let column = table.columns.iter().find(|column| column.name == name);
         match column {
             Some(column) => {
-                if type_oid != column.type_.postgres_type().oid() {
+                let type_matches = match &column.type_ {
+                    _ if column.encrypt => type_oid == postgres::types::BYTEA.oid(),
+                    // Enum types don't have a fixed OID, so they are compared by name.
+                    Type::Enum(enum_name) => postgres_type == *enum_name || postgres_type == column.type_.postgres_type_name(),
+                    type_ => type_oid == type_.postgres_type().oid() && type_modifier == type_.postgres_type_modifier(),
+                };
+                if !type_matches {
                     return Err(DbError::StructureError(format!(
                         "table \"{}\" has column \"{}\" of type \"{}\", which does not match type \"{}\" configured in the schema",
-                        table.name, name, postgres_type, column.type_.postgres_type_name())))
+                        table.name, name, postgres_type, column.postgres_type_name())))
                 }
                 if required && !column.required {
                     return Err(DbError::StructureError(format!(
                         "table \"{}\" has non-nullable column \"{}\" which is not required in the schema",
                         table.name, name)))
                 }
+                if !default_matches(column, default.as_deref(), conn)? {
+                    return Err(DbError::StructureError(format!(
+                        "table \"{}\" has column \"{}\" with default {}, which does not match default {} configured in the schema",
+                        table.name, name,
+                        default.as_deref().unwrap_or("none"),
+                        column.default.as_ref().map_or("none".to_string(), |default| default.to_string()))))
+                }
             }
             None => {
                 if required {
@@ -145,5 +953,581 @@ fn check_table(table: &Table, conn: &GenericConnection) -> Result<(), DbError> {
                 table.name, column.name)));
         }
     }
+    if table.id_column && !existing_columns.iter().any(|c| c.get::<&str, String>("name") == ID_COLUMN_NAME) {
+        return Err(DbError::StructureError(format!(
+            "table \"{}\" is missing the \"{}\" column for id_column configured in the schema",
+            table.name, ID_COLUMN_NAME)));
+    }
+
+    if !table.unique_key.is_empty() && !has_unique_index(conn, &table.name, &table.unique_key, false)? {
+        return Err(DbError::StructureError(format!(
+            "table \"{}\" has no unique constraint on the unique_key ({}) configured in the schema",
+            table.name, table.unique_key.join(", "))));
+    }
+    if !table.primary_key.is_empty() && !has_unique_index(conn, &table.name, &table.primary_key, true)? {
+        return Err(DbError::StructureError(format!(
+            "table \"{}\" does not have the primary_key ({}) configured in the schema",
+            table.name, table.primary_key.join(", "))));
+    }
+
+    let existing_constraints = conn.query(r#"
+        SELECT
+            con.conname as "name",
+            pg_catalog.obj_description(con.oid, 'pg_constraint') as "allowed_values"
+        FROM
+            pg_catalog.pg_constraint con
+            JOIN pg_catalog.pg_class c ON c.oid = con.conrelid
+        WHERE
+            con.contype = 'c'
+            AND c.relname = $1
+            AND pg_catalog.pg_table_is_visible(c.oid)
+        "#, &[&table.name])?;
+    for column in &table.columns {
+        let constraint_name = check_constraint_name(table, column);
+        let existing_values = existing_constraints.iter()
+            .find(|c| c.get::<&str, String>("name") == constraint_name)
+            .map(|c| c.get::<&str, Option<String>>("allowed_values")
+                .and_then(|comment| serde_json::from_str::<Vec<serde_json::Value>>(&comment).ok()));
+        match (&column.allowed_values, existing_values) {
+            (None, None) => {}
+            (Some(allowed_values), Some(Some(existing_values)))
+                if allowed_values.iter().all(|value| is_allowed(&existing_values, value)) &&
+                    existing_values.iter().all(|value| is_allowed(allowed_values, value)) => {}
+            (Some(_), _) => {
+                return Err(DbError::StructureError(format!(
+                    "table \"{}\" has no constraint \"{}\" matching the allowed_values of column \"{}\" configured in the schema",
+                    table.name, constraint_name, column.name)));
+            }
+            (None, Some(_)) => {
+                return Err(DbError::StructureError(format!(
+                    "table \"{}\" has constraint \"{}\", but column \"{}\" has no allowed_values in the schema",
+                    table.name, constraint_name, column.name)));
+            }
+        }
+    }
     Ok(())
 }
+
+#[test]
+fn partition_creation_query_bounds() {
+    let query = partition_creation_query("events", "events_2019_12", 2019, 12);
+    assert!(query.contains(r#""events_2019_12" PARTITION OF "events""#));
+    assert!(query.contains("FROM ('2019-12-01 00:00:00+00') TO ('2020-01-01 00:00:00+00')"));
+}
+
+#[test]
+fn partition_end_of_month() {
+    assert_eq!(partition_end("events", "events_2019_04"), Some(Utc.ymd(2019, 5, 1).and_hms(0, 0, 0)));
+    assert_eq!(partition_end("events", "events_2019_12"), Some(Utc.ymd(2020, 1, 1).and_hms(0, 0, 0)));
+    assert_eq!(partition_end("events", "events_2019_13"), None);
+    assert_eq!(partition_end("events", "events_archive"), None);
+    assert_eq!(partition_end("events", "other_2019_04"), None);
+    assert_eq!(partition_end("events", "events_201é_1"), None);
+    assert_eq!(partition_end("events", "events_2019é4"), None);
+    assert_eq!(partition_end("evénts", "evénts_2019_04"), Some(Utc.ymd(2019, 5, 1).and_hms(0, 0, 0)));
+}
+
+#[test]
+fn creation_query_check_constraint() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: platform
+                allowed_values: [android, "it's"]
+              - name: level
+                type: i32
+                allowed_values: [1, 2]
+        apps: {}
+        "#).unwrap();
+    let query = creation_query(&schema.tables["events"]);
+    assert!(query.contains(r#"CONSTRAINT "events_platform_allowed_values" CHECK ("platform" IN ('android', 'it''s'))"#));
+    assert!(query.contains(r#"CONSTRAINT "events_level_allowed_values" CHECK ("level" IN (1, 2))"#));
+}
+
+#[test]
+fn error_kinds() {
+    assert_eq!(DbError::StructureError("no such table".to_string()).kind(), "structure");
+    assert_eq!(DbError::ConversionError("score".to_string(), ConversionError::Negative("score".to_string())).kind(), "conversion");
+}
+
+#[test]
+fn statement_timeout_in_milliseconds() {
+    assert_eq!(statement_timeout_query(Duration::from_secs(5)), "SET statement_timeout = 5000");
+}
+
+#[test]
+fn schema_sql_statements() {
+    let schema = Schema::from_yaml(r#"
+        enums: {platform_type: [ios, android]}
+        tables:
+          b: {columns: [{name: platform, type: enum(platform_type)}]}
+          a: {columns: [{name: level, type: i32, allowed_values: [1, 2]}]}
+        apps: {}
+        "#).unwrap();
+    let statements = schema_sql(&schema).lines().map(str::to_string).collect::<Vec<_>>();
+    assert_eq!(statements.len(), 4);
+    assert_eq!(statements[0], r#"CREATE TYPE "platform_type" AS ENUM ('ios', 'android');"#);
+    assert!(statements[1].starts_with(r#"CREATE TABLE "a" ("#));
+    assert!(statements[2].starts_with(r#"COMMENT ON CONSTRAINT "a_level_allowed_values" ON "a" IS "#));
+    assert!(statements[3].starts_with(r#"CREATE TABLE "b" ("#));
+    assert!(statements.iter().all(|statement| statement.ends_with(';')));
+}
+
+#[test]
+fn creation_query_char_column() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: country
+                type: char(2)
+        apps: {}
+        "#).unwrap();
+    assert!(creation_query(&schema.tables["events"]).contains(r#""country" CHAR(2)"#));
+}
+
+#[test]
+fn creation_query_quoted_column_names() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: device.os
+              - name: userId
+                required: true
+        apps: {}
+        "#).unwrap();
+    let query = creation_query(&schema.tables["events"]);
+    assert!(query.contains(r#"CREATE TABLE "events" ("device.os" varchar, "userId" varchar not null)"#), "{}", query);
+}
+
+#[test]
+fn creation_query_id_column() {
+    let mut table = example_table();
+    table.id_column = true;
+    let query = creation_query(&table);
+    assert!(query.contains(r#"("id" BIGSERIAL, "time" timestamptz"#));
+    assert!(query.contains(r#", PRIMARY KEY ("id"))"#));
+    table.partition_by = Some("time".to_string());
+    assert!(creation_query(&table).contains(r#", PRIMARY KEY ("id", "time"))"#));
+}
+
+#[test]
+fn creation_query_primary_key() {
+    let mut table = example_table();
+    table.primary_key = vec!["user_id".to_string(), "time".to_string()];
+    let query = creation_query(&table);
+    assert!(query.contains(r#"CREATE TABLE "events" ("time" timestamptz"#));
+    assert!(query.contains(r#", PRIMARY KEY ("user_id", "time")"#));
+    table.on_conflict = OnConflict::Update;
+    assert!(insertion_query(&table).contains(r#" ON CONFLICT ("user_id", "time") DO UPDATE SET "referer" = EXCLUDED."referer", "#));
+}
+
+#[test]
+fn insertion_query_on_conflict() {
+    let mut table = example_table();
+    assert!(!insertion_query(&table).contains("ON CONFLICT"));
+    table.on_conflict = OnConflict::Ignore;
+    assert!(insertion_query(&table).ends_with(") ON CONFLICT DO NOTHING"));
+    table.unique_key = vec!["user_id".to_string(), "time".to_string()];
+    table.on_conflict = OnConflict::Update;
+    let query = insertion_query(&table);
+    assert!(query.contains(r#" ON CONFLICT ("user_id", "time") DO UPDATE SET "referer" = EXCLUDED."referer", "#));
+    assert!(!query.contains(r#""time" = EXCLUDED"#));
+    assert!(creation_query(&table).contains(r#", UNIQUE ("user_id", "time")"#));
+}
+
+#[test]
+fn rollup_queries() {
+    let schema = Schema::from_yaml(r#"
+        enums: {platform_type: [ios, android]}
+        tables:
+          events:
+            on_conflict: ignore
+            columns:
+              - name: time
+                type: timestamp
+                required: true
+              - name: platform
+                type: enum(platform_type)
+                required: true
+              - name: event_type
+                required: true
+            rollups:
+              - name: events_hourly
+                time_column: time
+                bucket: hour
+                group_by: [platform, event_type]
+        apps: {}
+        "#).unwrap();
+    let table = &schema.tables["events"];
+    assert_eq!(table.rollups[0].upsert_query, concat!(
+        r#"INSERT INTO "events_hourly" ("bucket", "platform", "event_type", "count") "#,
+        r#"VALUES (date_trunc('hour', $1::TIMESTAMPTZ AT TIME ZONE 'UTC') AT TIME ZONE 'UTC', $2::TEXT::"platform_type", $3, 1) "#,
+        r#"ON CONFLICT ("bucket", "platform", "event_type") DO UPDATE SET "count" = "events_hourly"."count" + 1"#));
+    let sql = schema_sql(&schema);
+    assert!(sql.contains(r#"CREATE TABLE "events_hourly" ("bucket" timestamptz not null, "platform" "platform_type" not null, "event_type" varchar not null, "count" int8 not null, PRIMARY KEY ("bucket", "platform", "event_type"));"#), "{}", sql);
+}
+
+#[test]
+fn original_event_type() {
+    assert_eq!(event_type(&serde_json::json!({"_t": "a"})).as_deref(), Some("a"));
+    assert_eq!(event_type(&serde_json::json!({"_t": ["a", "b"]})).as_deref(), Some("a,b"));
+    assert_eq!(event_type(&serde_json::json!({"_t": "other", "_original_t": "a"})).as_deref(), Some("a"));
+    assert_eq!(event_type(&serde_json::json!({"_t": 1})), None);
+    assert_eq!(event_type(&serde_json::json!({})), None);
+}
+
+#[test]
+fn event_table_names() {
+    assert_eq!(table_names(&serde_json::json!({"_t": "a"})), Some(vec!["a"]));
+    assert_eq!(table_names(&serde_json::json!({"_t": ["a", "b"]})), Some(vec!["a", "b"]));
+    assert_eq!(table_names(&serde_json::json!({"_t": []})), None);
+    assert_eq!(table_names(&serde_json::json!({"_t": ["a", 1]})), None);
+    assert_eq!(table_names(&serde_json::json!({})), None);
+}
+
+#[test]
+fn lookup_dotted_keys() {
+    let event = serde_json::json!({
+        "flat": 1,
+        "device.os": "verbatim",
+        "device": {"os": "ios", "screen.size": 5},
+    });
+    assert_eq!(lookup(&event, "flat"), &serde_json::json!(1));
+    assert_eq!(lookup(&event, "device.os"), &serde_json::json!("verbatim"));
+    assert_eq!(lookup(&event, r"device.screen\.size"), &serde_json::json!(5));
+    assert_eq!(lookup(&event, "device.model"), &NULL);
+    assert_eq!(lookup(&event, "flat.nested"), &NULL);
+}
+
+/// Returns the metadata of a POST request with the given headers, and none of the optional parts.
+#[cfg(test)]
+fn test_metadata<'a>(headers: &'a HeaderMap<'a>) -> RequestMetadata<'a> {
+    RequestMetadata {
+        method: "POST",
+        path: "/apps/app/events",
+        headers,
+        received_at: Utc::now(),
+        client_ip: None,
+        geoip: None,
+        encryption: None,
+        body_size: None,
+        batch_id: None,
+        query: None,
+    }
+}
+
+#[test]
+fn event_values_from_header() {
+    let table = example_table();
+    let mut headers = HeaderMap::new();
+    headers.add_raw("referer", "https://example.com/");
+    let metadata = test_metadata(&headers);
+    let event = serde_json::json!({"_t": "events", "platform": "ios", "version": "1.0", "event_type": "start", "referer": "ignored"});
+    let values = event_values(&table, &event, &metadata).unwrap();
+    assert_eq!(table.columns[1].name, "referer");
+    assert_eq!(format!("{:?}", values[1]), format!("{:?}", Some("https://example.com/")));
+}
+
+#[test]
+fn missing_required_header() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: user_agent
+                header: User-Agent
+                required: true
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    let event = serde_json::json!({"_t": "events", "user_agent": "ignored"});
+    let result = event_values(&schema.tables["events"], &event, &metadata).map(|values| values.len());
+    match result {
+        Err(DbError::ConversionError(ref column, ConversionError::MissingValue(_))) if column == "user_agent" => {}
+        _ => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn fill_missing_timestamp() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: time
+                type: timestamp
+                required: true
+                fill_if_absent: now
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    for event in &[serde_json::json!({"_t": "events"}), serde_json::json!({"_t": "events", "time": 1554130180})] {
+        assert_eq!(event_values(&schema.tables["events"], event, &metadata).map(|values| values.len()).unwrap(), 1);
+    }
+    let event = serde_json::json!({"_t": "events", "time": "yesterday"});
+    assert!(event_values(&schema.tables["events"], &event, &metadata).is_err());
+}
+
+#[test]
+fn unknown_event_keys() {
+    let table = example_table();
+    let event = serde_json::json!({"_t": "events", "platform": "ios", "platfrom": "ios", "referer": "x", "_meta": 1});
+    assert_eq!(unknown_keys(&[&table], &event), vec!["platfrom", "referer"]);
+}
+
+#[cfg(test)]
+fn example_table() -> Table {
+    let schema = Schema::from_yaml(&std::fs::read_to_string("schema-example.conf.yaml").unwrap()).unwrap();
+    schema.tables["events"].clone()
+}
+
+#[cfg(test)]
+#[bench]
+fn bench_insertion_query(b: &mut test::Bencher) {
+    let table = example_table();
+    b.iter(|| insertion_query(test::black_box(&table)));
+}
+
+#[cfg(test)]
+#[bench]
+fn bench_cached_insert_query(b: &mut test::Bencher) {
+    let table = example_table();
+    b.iter(|| test::black_box(&table).insert_query.len());
+}
+
+#[test]
+fn default_for_missing_value() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: level
+                type: i64
+                default: 1
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    let table = &schema.tables["events"];
+    let missing = serde_json::json!({"_t": "events"});
+    let values = event_values(table, &missing, &metadata).unwrap();
+    assert_eq!(format!("{:?}", values[0]), format!("{:?}", Some(1)));
+    let given = serde_json::json!({"_t": "events", "level": 5});
+    let values = event_values(table, &given, &metadata).unwrap();
+    assert_eq!(format!("{:?}", values[0]), format!("{:?}", Some(5)));
+    assert!(creation_query(table).contains(r#""level" int8 DEFAULT 1"#));
+}
+
+#[test]
+fn prepare_event_reports_field() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          a: {columns: [{name: x, type: i32}]}
+          b: {columns: [{name: y, type: bool, required: true}]}
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    let valid = serde_json::json!({"_t": ["a", "b"], "x": 1, "y": true});
+    assert_eq!(PreparedEvent::new(&schema, "", &valid, &metadata).unwrap().rows.len(), 2);
+    let invalid = serde_json::json!({"_t": ["a", "b"], "x": 1});
+    match PreparedEvent::new(&schema, "", &invalid, &metadata) {
+        Err(DbError::ConversionError(ref field, ConversionError::MissingValue(_))) if field == "y" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    let unknown = serde_json::json!({"_t": "c"});
+    assert!(PreparedEvent::new(&schema, "", &unknown, &metadata).is_err());
+}
+
+#[test]
+fn normalized_string_value() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: platform
+                lowercase: true
+                trim: true
+                allowed_values: [ios, android]
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    let table = &schema.tables["events"];
+    let event = serde_json::json!({"_t": "events", "platform": " iOS "});
+    let values = event_values(table, &event, &metadata).unwrap();
+    assert_eq!(format!("{:?}", values[0]), format!("{:?}", Some("ios".to_string())));
+}
+
+#[test]
+fn conditionally_required_value() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: event_type
+              - name: price
+                type: f64
+                required_if: {column: event_type, equals: purchase}
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    let table = &schema.tables["events"];
+    assert!(event_values(table, &serde_json::json!({"_t": "events", "event_type": "start"}), &metadata).is_ok());
+    assert!(event_values(table, &serde_json::json!({"_t": "events", "event_type": "purchase", "price": 1.5}), &metadata).is_ok());
+    match event_values(table, &serde_json::json!({"_t": "events", "event_type": "purchase"}), &metadata) {
+        Err(DbError::ConversionError(ref field, ConversionError::MissingValue(_))) if field == "price" => {}
+        result => panic!("unexpected result: {:?}", result.map(|values| values.len())),
+    }
+}
+
+#[test]
+fn batch_id_from_metadata() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: batch_id
+                type: uuid
+                source: batch_id
+                required: true
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let batch_id = crate::types::random_uuid();
+    let metadata = RequestMetadata { batch_id: Some(&batch_id), ..test_metadata(&headers) };
+    let table = &schema.tables["events"];
+    let event = serde_json::json!({"_t": "events"});
+    let values = event_values(table, &event, &metadata).unwrap();
+    assert_eq!(format!("{:?}", values[0]), format!("{:?}", batch_id.as_str()));
+    assert!(insertion_query(table).contains("VALUES ($1::TEXT::UUID)"));
+}
+
+#[test]
+fn raw_event_as_text() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: level
+                type: i32
+              - name: raw
+                source: raw_event
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    let event = serde_json::json!({"_t": "events", "level": 3, "extra": [1, 2]});
+    let values = event_values(&schema.tables["events"], &event, &metadata).unwrap();
+    assert_eq!(values.len(), 2);
+    assert_eq!(format!("{:?}", values[1]), format!("{:?}", Some(event.to_string())));
+}
+
+#[test]
+fn non_finite_event_values() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: score
+                type: f64
+              - name: ratio
+                type: f64
+                allow_non_finite: true
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = test_metadata(&headers);
+    let table = &schema.tables["events"];
+    match event_values(table, &serde_json::json!({"_t": "events", "score": "NaN"}), &metadata) {
+        Err(DbError::ConversionError(ref field, ConversionError::NonFinite(_))) if field == "score" => {}
+        result => panic!("unexpected result: {:?}", result.map(|values| values.len())),
+    }
+    let values = event_values(table, &serde_json::json!({"_t": "events", "score": 1.5, "ratio": "Infinity"}), &metadata).unwrap();
+    assert_eq!(format!("{:?}", values[1]), format!("{:?}", Some(std::f64::INFINITY)));
+}
+
+#[test]
+fn encrypted_event_values() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: user_id
+                encrypt: true
+                required: true
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let encryption = Encryption::from_hex_key(&"01".repeat(32)).unwrap();
+    let mut metadata = RequestMetadata { encryption: Some(&encryption), ..test_metadata(&headers) };
+    let table = &schema.tables["events"];
+    let event = serde_json::json!({"_t": "events", "user_id": "user 42"});
+    let values = event_values(table, &event, &metadata).unwrap();
+    assert!(!format!("{:?}", values[0]).contains("user 42"));
+    assert!(creation_query(table).contains(r#""user_id" BYTEA not null"#));
+    metadata.encryption = None;
+    match event_values(table, &event, &metadata).map(|values| values.len()) {
+        Err(DbError::StructureError(_)) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn event_values_from_query() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: campaign
+                source: query:utm_campaign
+              - name: medium
+                source: query:utm_medium
+        apps: {}
+        "#).unwrap();
+    let headers = HeaderMap::new();
+    let metadata = RequestMetadata { query: Some("partial=true&utm_campaign=spring%20sale&utm_campaign=other"), ..test_metadata(&headers) };
+    let event = serde_json::json!({"_t": "events", "medium": "ignored"});
+    let values = event_values(&schema.tables["events"], &event, &metadata).unwrap();
+    assert_eq!(format!("{:?}", values[0]), format!("{:?}", Some("spring sale".to_string())));
+    assert_eq!(format!("{:?}", values[1]), format!("{:?}", None::<String>));
+}
+
+#[test]
+fn deletion_queries() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: user_id
+                indexed: true
+              - name: active
+                type: bool
+                indexed: true
+              - name: session_id
+                type: uuid
+                indexed: true
+              - name: platform
+        apps: {}
+        "#).unwrap();
+    let table = &schema.tables["events"];
+    let filter = |column_name: &str, value: &str| (column_name.to_string(), value.to_string());
+    let (query, values) = deletion_query(table, &[filter("user_id", "42"), filter("active", "true")]).unwrap();
+    assert_eq!(query, r#"DELETE FROM "events" WHERE "user_id" = $1 AND "active" = $2"#);
+    assert_eq!(format!("{:?}", values), r#"["42", true]"#);
+    let (query, _) = deletion_query(table, &[filter("session_id", "123e4567-e89b-12d3-a456-426614174000")]).unwrap();
+    assert_eq!(query, r#"DELETE FROM "events" WHERE "session_id" = $1::TEXT::UUID"#);
+
+    for filters in &[vec![], vec![filter("platform", "android")], vec![filter("user_id", "42"), filter("missing", "1")]] {
+        match deletion_query(table, filters) {
+            Err(DbError::StructureError(_)) => {}
+            result => panic!("unexpected result for {:?}: {:?}", filters, result.map(|(query, _)| query)),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+/// Length of the random nonce that is stored in front of each encrypted value.
+const NONCE_LENGTH: usize = 12;
+
+/// Encrypts and decrypts the values of columns that have `encrypt` set, with AES-256-GCM using the
+/// key given by `--encryption-key`. Every value gets a random nonce, so equal values are stored
+/// differently.
+pub struct Encryption {
+    cipher: Aes256Gcm,
+}
+
+impl std::fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "Encryption {{ .. }}")
+    }
+}
+
+impl Encryption {
+    /// Creates an `Encryption` from a key of 32 bytes, written as 64 hexadecimal digits.
+    pub fn from_hex_key(hex_key: &str) -> Result<Encryption, String> {
+        let key = hex::decode(hex_key.trim()).map_err(|err| err.to_string())?;
+        if key.len() != 32 {
+            return Err(format!("expected 32 bytes (64 hexadecimal digits), got {} bytes", key.len()));
+        }
+        Ok(Encryption { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)) })
+    }
+
+    /// Returns the nonce followed by the ciphertext and authentication tag.
+    pub fn encrypt(&self, plaintext: &str) -> Vec<u8> {
+        let nonce = rand::random::<[u8; NONCE_LENGTH]>();
+        // Encryption only fails if the plaintext is impossibly large.
+        let ciphertext = self.cipher.encrypt(Nonce::from_slice(&nonce), plaintext.as_bytes()).unwrap();
+        nonce.iter().cloned().chain(ciphertext).collect()
+    }
+
+    /// Reverses `encrypt`. Returns `None` if the data was not encrypted with the same key, or was
+    /// tampered with.
+    pub fn decrypt(&self, data: &[u8]) -> Option<String> {
+        if data.len() < NONCE_LENGTH {
+            return None;
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+        self.cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+            .and_then(|plaintext| String::from_utf8(plaintext).ok())
+    }
+}
+
+#[test]
+fn encrypt_and_decrypt() {
+    let encryption = Encryption::from_hex_key(&"01".repeat(32)).unwrap();
+    let encrypted = encryption.encrypt("user 42");
+    assert_ne!(encryption.encrypt("user 42"), encrypted);
+    assert_eq!(encryption.decrypt(&encrypted).as_deref(), Some("user 42"));
+    let other = Encryption::from_hex_key(&"02".repeat(32)).unwrap();
+    assert_eq!(other.decrypt(&encrypted), None);
+    assert_eq!(encryption.decrypt(b"short"), None);
+    assert!(Encryption::from_hex_key("0102").is_err());
+    assert!(Encryption::from_hex_key("not hex").is_err());
+}
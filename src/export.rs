@@ -0,0 +1,41 @@
+use crate::db::{ID_COLUMN_NAME, StoredEvent};
+use crate::schema::Table;
+
+/// Writes events as CSV, with a header row containing the `id` column followed by the table's
+/// columns in schema order. Nulls are written as empty fields.
+pub fn to_csv(table: &Table, events: &[StoredEvent]) -> Result<Vec<u8>, csv::Error> {
+    let column_names = Some(ID_COLUMN_NAME).into_iter()
+        .chain(table.columns.iter().map(|column| column.name.as_str()))
+        .collect::<Vec<_>>();
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(&column_names)?;
+    for event in events {
+        writer.write_record(column_names.iter().map(|column_name| match event.get(*column_name) {
+            None | Some(serde_json::Value::Null) => "".to_string(),
+            Some(serde_json::Value::String(string)) => string.to_string(),
+            Some(value) => value.to_string(),
+        }))?;
+    }
+    writer.into_inner().map_err(|err| err.into_error().into())
+}
+
+#[test]
+fn events_to_csv() {
+    let schema = crate::schema::Schema::from_yaml(r#"
+        tables:
+          events:
+            id_column: true
+            columns:
+              - name: user_id
+              - name: score
+                type: i32
+        apps: {}
+        "#).unwrap();
+    let events = vec![
+        serde_json::json!({"id": 1, "user_id": "a, \"b\"", "score": 42}),
+        serde_json::json!({"id": 2, "user_id": null, "score": null}),
+    ];
+    let events = events.into_iter().map(|event| event.as_object().unwrap().clone()).collect::<Vec<_>>();
+    let csv = String::from_utf8(to_csv(&schema.tables["events"], &events).unwrap()).unwrap();
+    assert_eq!(csv, "id,user_id,score\n1,\"a, \"\"b\"\"\",42\n2,,\n");
+}
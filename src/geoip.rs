@@ -0,0 +1,32 @@
+use std::fmt::Debug;
+use std::net::IpAddr;
+use std::path::Path;
+
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+
+/// A MaxMind GeoIP2 or GeoLite2 database, used to populate `geoip_country` columns.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(path: &Path) -> Result<GeoIp, MaxMindDBError> {
+        Ok(GeoIp { reader: Reader::open_readfile(path)? })
+    }
+
+    /// Returns the ISO 3166-1 code of the country that the address belongs to, if it is known.
+    /// Private and reserved addresses are not in the database, so they return `None`.
+    pub fn country(&self, ip: IpAddr) -> Option<String> {
+        self.reader.lookup::<geoip2::Country>(ip).ok()
+            .and_then(|country| country.country)
+            .and_then(|country| country.iso_code)
+            .map(str::to_string)
+    }
+}
+
+// Not derived, because that would print the entire database.
+impl Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "GeoIp({})", self.reader.metadata.database_type)
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+
+use serde_json::json;
+
+use crate::schema::{App, Column, KeyScope, Schema, Table};
+use crate::types::Type;
+
+/// Returns a JSON Schema (draft 7) document describing the events that the app may send, so that
+/// clients can validate them before sending. Each of the app's tables that the key in `scope` may
+/// write to is a definition, and an event must match one of them. Only columns that are populated
+/// from the event are described; those with a `header` or `source` are not. Only columns that must
+/// be sent are required, so not those with a `default` or `fill_if_absent`.
+pub fn app_json_schema(schema: &Schema, app: &App, scope: KeyScope) -> serde_json::Value {
+    let definitions = app.tables.iter()
+        .filter(|table_name| scope.allows(table_name))
+        .map(|table_name| (table_name.to_string(), table_json_schema(schema, table_name, &schema.tables[&format!("{}{}", app.table_prefix(), table_name)])))
+        .collect::<serde_json::Map<_, _>>();
+    let one_of = definitions.keys()
+        .map(|table_name| json!({"$ref": format!("#/definitions/{}", table_name)}))
+        .collect::<Vec<_>>();
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": format!("Events of app {}", app.app_id),
+        "definitions": definitions,
+        "oneOf": one_of,
+    })
+}
+
+/// Returns the schema of an event for the table, whose name as the app knows it is `table_name`.
+fn table_json_schema(schema: &Schema, table_name: &str, table: &Table) -> serde_json::Value {
+    let columns = table.columns.iter()
+        .filter(|column| column.header.is_none() && column.source.is_none())
+        .collect::<Vec<_>>();
+    let mut properties = serde_json::Map::new();
+    properties.insert("_t".to_string(), json!({"const": table_name}));
+    for column in &columns {
+        properties.insert(column.json_key().to_string(), column_json_schema(&schema.enums, column));
+    }
+    let required = Some("_t").into_iter()
+        .chain(columns.iter().filter(|column| column.must_be_sent()).map(|column| column.json_key()))
+        .collect::<Vec<_>>();
+    let conditions = columns.iter()
+        .filter_map(|column| column.required_if.as_ref().map(|required_if| (column, required_if)))
+        .filter_map(|(column, required_if)| {
+            let condition_column = table.columns.iter().find(|other| other.name == required_if.column)?;
+            Some(json!({
+                "if": {
+                    "properties": {condition_column.json_key(): {"const": required_if.equals}},
+                    "required": [condition_column.json_key()],
+                },
+                "then": {"required": [column.json_key()]},
+            }))
+        })
+        .collect::<Vec<_>>();
+    let mut table_schema = json!({
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    });
+    // Fields starting with an underscore are metadata, which strict tables also allow.
+    if table.strict {
+        table_schema["patternProperties"] = json!({"^_": {}});
+        table_schema["additionalProperties"] = json!(false);
+    }
+    if !conditions.is_empty() {
+        table_schema["allOf"] = json!(conditions);
+    }
+    table_schema
+}
+
+fn column_json_schema(enums: &HashMap<String, Vec<String>>, column: &Column) -> serde_json::Value {
+    let mut column_schema = type_json_schema(enums, &column.type_);
+    if let Some(allowed_values) = &column.allowed_values {
+        column_schema["enum"] = json!(allowed_values);
+    }
+    if let Some(default) = &column.default {
+        column_schema["default"] = default.clone();
+    }
+    column_schema
+}
+
+/// Returns the schema of the values that are accepted for a column of the given type. Numbers are
+/// also accepted as strings, but the schema only describes the canonical form.
+fn type_json_schema(enums: &HashMap<String, Vec<String>>, type_: &Type) -> serde_json::Value {
+    match type_ {
+        Type::Bool => json!({"type": "boolean"}),
+        Type::I32 => json!({"type": "integer", "minimum": i32::MIN, "maximum": i32::MAX}),
+        Type::I64 => json!({"type": "integer", "minimum": i64::MIN, "maximum": i64::MAX}),
+        Type::U64 => json!({"type": "integer", "minimum": 0, "maximum": u64::MAX}),
+        Type::F32 | Type::F64 => json!({"type": "number"}),
+        Type::String => json!({"type": "string"}),
+        Type::Char(length) => json!({"type": "string", "maxLength": length}),
+        Type::Timestamp => json!({"anyOf": [{"type": "number"}, {"type": "string", "format": "date-time"}]}),
+        Type::TimestampNaive => json!({"anyOf": [{"type": "number"}, {"type": "string"}]}),
+        Type::Interval => json!({"anyOf": [{"type": "number"}, {"type": "string", "format": "duration"}]}),
+        Type::Jsonb => json!({}),
+        Type::Uuid => json!({"type": "string", "format": "uuid"}),
+        Type::Enum(enum_name) => json!({"type": "string", "enum": enums.get(enum_name).cloned().unwrap_or_default()}),
+    }
+}
+
+#[test]
+fn json_schema_of_app() {
+    let schema = Schema::from_yaml(r#"
+        enums: {platform_type: [ios, android]}
+        tables:
+          events:
+            strict: true
+            columns:
+              - name: event_type
+                required: true
+              - name: platform
+                type: enum(platform_type)
+              - name: level
+                key: lvl
+                type: i32
+                required: true
+                default: 1
+              - name: price
+                type: f64
+                required_if: {column: event_type, equals: purchase}
+              - name: agent
+                header: User-Agent
+          other:
+            columns:
+              - name: country
+                type: char(2)
+        apps:
+          app:
+            secret_key: secret
+            tables: [events, other]
+        "#).unwrap();
+    let app = &schema.apps["app"];
+    let json_schema = app_json_schema(&schema, app, KeyScope::AllTables);
+    assert_eq!(json_schema["oneOf"], json!([{"$ref": "#/definitions/events"}, {"$ref": "#/definitions/other"}]));
+    let events = &json_schema["definitions"]["events"];
+    assert_eq!(events["properties"]["_t"], json!({"const": "events"}));
+    assert_eq!(events["properties"]["platform"], json!({"type": "string", "enum": ["ios", "android"]}));
+    assert_eq!(events["properties"]["lvl"]["default"], json!(1));
+    assert!(events["properties"].get("agent").is_none());
+    assert_eq!(events["required"], json!(["_t", "event_type"]));
+    assert_eq!(events["additionalProperties"], json!(false));
+    assert_eq!(events["allOf"][0]["then"], json!({"required": ["price"]}));
+    assert_eq!(json_schema["definitions"]["other"]["properties"]["country"], json!({"type": "string", "maxLength": 2}));
+
+    let only_other = ["other".to_string()];
+    let json_schema = app_json_schema(&schema, app, KeyScope::Tables(&only_other));
+    assert_eq!(json_schema["oneOf"], json!([{"$ref": "#/definitions/other"}]));
+}
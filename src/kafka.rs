@@ -0,0 +1,63 @@
+use std::fmt::Debug;
+
+use rdkafka::ClientContext;
+use rdkafka::config::ClientConfig;
+use rdkafka::error::KafkaError;
+use rdkafka::message::Message;
+use rdkafka::producer::{BaseRecord, DeliveryResult, ProducerContext, ThreadedProducer};
+use tracing::error;
+
+use crate::db;
+use crate::schema::Schema;
+
+/// Logs messages that could not be delivered.
+struct LoggingContext;
+
+impl ClientContext for LoggingContext {}
+
+impl ProducerContext for LoggingContext {
+    type DeliveryOpaque = ();
+
+    fn delivery(&self, delivery_result: &DeliveryResult, _: Self::DeliveryOpaque) {
+        if let Err((err, message)) = delivery_result {
+            error!(topic = message.topic(), error = %err, "failed to publish event to Kafka");
+        }
+    }
+}
+
+/// Forwards inserted events to the Kafka topics configured by `kafka_topic` on their tables. The
+/// producer retries and queues by itself, so unlike webhooks and the archive, this doesn't need a
+/// `RetryQueue`; failures are only logged in the same way.
+pub struct Kafka {
+    producer: ThreadedProducer<LoggingContext>,
+}
+
+impl Kafka {
+    pub fn new(brokers: &str) -> Result<Kafka, KafkaError> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .create_with_context(LoggingContext)?;
+        Ok(Kafka { producer })
+    }
+
+    /// Queues the event for publishing to the topic of each table it was inserted into, if any.
+    pub fn publish(&self, schema: &Schema, event: &serde_json::Value) {
+        let topics = db::table_names(event).unwrap_or_default().into_iter()
+            .filter_map(|table_name| schema.tables.get(table_name))
+            .filter_map(|table| table.kafka_topic.as_ref());
+        let mut payload = None;
+        for topic in topics {
+            let payload = payload.get_or_insert_with(|| event.to_string());
+            if let Err((err, _)) = self.producer.send(BaseRecord::<(), _>::to(topic).payload(payload.as_str())) {
+                error!(%topic, error = %err, "failed to publish event to Kafka");
+            }
+        }
+    }
+}
+
+// Not derived, because the producer doesn't implement Debug.
+impl Debug for Kafka {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        write!(f, "Kafka")
+    }
+}
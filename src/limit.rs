@@ -0,0 +1,105 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use rocket::State;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use tracing::warn;
+
+use crate::metrics::Metrics;
+
+/// Limits the number of requests that are handled at the same time, so that a flood of requests
+/// is turned away instead of piling up while waiting for a database connection. Clones count
+/// towards the same limit.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimit {
+    max: Option<usize>,
+    current: Arc<AtomicUsize>,
+}
+
+/// Allows one request to be handled for as long as it is alive. As a request guard, it is taken
+/// from the managed `ConcurrencyLimit`, and the request fails with status 503 if there is none
+/// left. Rocket runs the data guard after all request guards, so a request is turned away before
+/// its body is read.
+#[derive(Debug)]
+pub struct Permit<'a> {
+    limit: &'a ConcurrencyLimit,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.limit.current.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max: Option<usize>) -> ConcurrencyLimit {
+        ConcurrencyLimit { max, current: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    /// Returns a permit to handle a request, or `None` if the maximum number of requests is
+    /// already being handled.
+    pub fn try_acquire(&self) -> Option<Permit> {
+        let previous = self.current.fetch_add(1, Ordering::SeqCst);
+        // If the limit was reached, dropping the permit undoes the increment.
+        let permit = Permit { limit: self };
+        match self.max {
+            Some(max) if previous >= max => None,
+            _ => Some(permit),
+        }
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Permit<'r> {
+    type Error = ();
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let limit = match request.guard::<State<ConcurrencyLimit>>().succeeded() {
+            Some(limit) => limit.inner(),
+            None => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        match limit.try_acquire() {
+            Some(permit) => Outcome::Success(permit),
+            None => {
+                warn!("too many concurrent requests, rejecting request");
+                if let Some(metrics) = request.guard::<State<Metrics>>().succeeded() {
+                    metrics.count("requests.shed", 1);
+                }
+                Outcome::Failure((Status::ServiceUnavailable, ()))
+            }
+        }
+    }
+}
+
+#[test]
+fn concurrency_limit() {
+    let limit = ConcurrencyLimit::new(Some(2));
+    let first = limit.try_acquire().unwrap();
+    let _second = limit.try_acquire().unwrap();
+    assert!(limit.try_acquire().is_none());
+    drop(first);
+    assert!(limit.try_acquire().is_some());
+    assert!(ConcurrencyLimit::new(None).try_acquire().is_some());
+}
+
+#[cfg(test)]
+#[post("/limited", data = "<body>")]
+fn limited(_permit: Permit, body: String) -> String {
+    body
+}
+
+#[test]
+fn permit_guard() {
+    let status = |max| {
+        let rocket = rocket::ignite()
+            .manage(ConcurrencyLimit::new(max))
+            .manage(Metrics::default())
+            .mount("/", routes![limited]);
+        let client = rocket::local::Client::new(rocket).unwrap();
+        let response = client.post("/limited").body("body").dispatch();
+        response.status()
+    };
+    assert_eq!(status(Some(1)), Status::Ok);
+    assert_eq!(status(Some(0)), Status::ServiceUnavailable);
+    assert_eq!(status(None), Status::Ok);
+}
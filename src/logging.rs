@@ -0,0 +1,108 @@
+use std::path::Path;
+
+use opentelemetry_sdk::trace::Tracer;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::rolling::{InitError, RollingFileAppender, Rotation};
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{Layer, Registry};
+
+/// How log lines are written, as given by `--log-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable lines, with the span fields in front of the message.
+    Text,
+    /// One JSON object per line, with the level, message and fields as keys, and the fields of the
+    /// enclosing spans (such as `request_id` and `app_id`) under `spans`.
+    Json,
+}
+
+impl LogFormat {
+    pub fn parse(s: &str) -> Option<LogFormat> {
+        match s {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Sets up the global logger. Log lines are written to standard output, or to `log_file` if given,
+/// along with the fields of the spans they were emitted in, such as the request ID and app ID of
+/// the request being handled. Log files are rotated daily, keeping at most `max_log_files` of them.
+///
+/// Rocket's own messages are routed through the same logger, so this must be called before Rocket
+/// is launched; Rocket then doesn't install its own.
+///
+/// If a `tracer` is given, spans are also exported with it, independently of the verbosity.
+pub fn init(verbosity: i32, format: LogFormat, log_file: Option<&Path>, max_log_files: usize, tracer: Option<Tracer>) -> Result<(), InitError> {
+    let writer = match log_file {
+        Some(log_file) => BoxMakeWriter::new(RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix(log_file.file_name().and_then(|name| name.to_str()).unwrap_or("attolytics.log"))
+            .max_log_files(max_log_files)
+            .build(log_file.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or_else(|| Path::new(".")))?),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_target(false);
+    // The layers have different types, so they are boxed to pick one at runtime.
+    let layer: Box<dyn Layer<Registry> + Send + Sync> = match format {
+        LogFormat::Text => layer
+            .with_ansi(log_file.is_none())
+            .with_filter(filter(verbosity))
+            .boxed(),
+        LogFormat::Json => layer
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_filter(filter(verbosity))
+            .boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer()
+            .with_tracer(tracer)
+            .with_filter(Targets::new().with_target("attolytics", LevelFilter::INFO))))
+        .init();
+    Ok(())
+}
+
+/// Mirrors the levels of Rocket's own logger at the corresponding `LoggingLevel`: launch messages
+/// are shown unless logging is off, and messages from the HTTP and TLS libraries only when
+/// debugging.
+fn filter(verbosity: i32) -> Targets {
+    let (level, library_level) = match verbosity {
+        1 => (LevelFilter::INFO, LevelFilter::ERROR),
+        2 => (LevelFilter::DEBUG, LevelFilter::INFO),
+        _ => if verbosity < 1 { (LevelFilter::OFF, LevelFilter::OFF) } else { (LevelFilter::TRACE, LevelFilter::DEBUG) },
+    };
+    Targets::new()
+        .with_default(library_level)
+        .with_target("attolytics", level)
+        .with_target("launch", level.min(LevelFilter::INFO))
+        .with_target("hyper", if verbosity >= 3 { library_level } else { LevelFilter::OFF })
+        .with_target("rustls", if verbosity >= 3 { library_level } else { LevelFilter::OFF })
+}
+
+#[test]
+fn parse_log_format() {
+    assert_eq!(LogFormat::parse("text"), Some(LogFormat::Text));
+    assert_eq!(LogFormat::parse("json"), Some(LogFormat::Json));
+    assert_eq!(LogFormat::parse("JSON"), None);
+}
+
+#[test]
+fn filter_levels() {
+    use tracing::Level;
+    assert!(!filter(0).would_enable("attolytics::db", &Level::ERROR));
+    assert!(filter(1).would_enable("attolytics::db", &Level::INFO));
+    assert!(filter(1).would_enable("launch", &Level::INFO));
+    assert!(!filter(1).would_enable("_", &Level::INFO));
+    assert!(filter(2).would_enable("_", &Level::INFO));
+    assert!(!filter(2).would_enable("hyper::server", &Level::ERROR));
+    assert!(filter(3).would_enable("hyper::server", &Level::DEBUG));
+}
@@ -0,0 +1,103 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cadence::{Counted, ErrorKind, MetricError, StatsdClient, Timed, UdpMetricSink};
+use tracing::info;
+
+/// Prefix of all metric names.
+const PREFIX: &str = "attolytics";
+
+/// Sends metrics to a StatsD server, if one was configured; otherwise, does nothing. Metrics are
+/// sent over UDP without waiting for a response, and errors are ignored. Clones send to the same
+/// client. Counts of events are also kept for the periodic summary, see `start_summary_thread`.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    client: Option<Arc<StatsdClient>>,
+    summary: Arc<Summary>,
+}
+
+/// Numbers of events since the last summary was logged.
+#[derive(Debug, Default)]
+struct Summary {
+    inserted: AtomicU64,
+    rejected: AtomicU64,
+    /// Events that could not be inserted because of a database error, including spooled ones.
+    failed: AtomicU64,
+}
+
+impl Summary {
+    fn count(&self, key: &str, count: u64) {
+        let counter = match key {
+            "events.inserted" => &self.inserted,
+            "events.rejected" => &self.rejected,
+            "events.failed" | "events.spooled" => &self.failed,
+            _ => return,
+        };
+        counter.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Returns the counts and resets them to zero.
+    fn take(&self) -> (u64, u64, u64) {
+        (self.inserted.swap(0, Ordering::Relaxed),
+         self.rejected.swap(0, Ordering::Relaxed),
+         self.failed.swap(0, Ordering::Relaxed))
+    }
+}
+
+impl Metrics {
+    pub fn statsd(addr: &str) -> Result<Metrics, MetricError> {
+        let addr = addr.to_socket_addrs()?.next()
+            .ok_or((ErrorKind::InvalidInput, "StatsD address did not resolve"))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+        socket.set_nonblocking(true)?;
+        let sink = UdpMetricSink::from(addr, socket)?;
+        Ok(Metrics { client: Some(Arc::new(StatsdClient::from_sink(PREFIX, sink))), summary: Arc::default() })
+    }
+
+    pub fn count(&self, key: &str, count: u64) {
+        self.summary.count(key, count);
+        if let Some(client) = &self.client {
+            let _ = client.count(key, count);
+        }
+    }
+
+    pub fn time(&self, key: &str, duration: Duration) {
+        if let Some(client) = &self.client {
+            let _ = client.time(key, duration);
+        }
+    }
+
+    /// Spawns a background thread that logs the number of events inserted, rejected and failed
+    /// every `interval`. Ticks are scheduled from the start time rather than from the end of the
+    /// previous tick, so they don't drift.
+    pub fn start_summary_thread(&self, interval: Duration) {
+        let summary = self.summary.clone();
+        thread::spawn(move || {
+            let start = Instant::now();
+            for tick in 1.. {
+                let next = start + interval * tick;
+                let now = Instant::now();
+                if next > now {
+                    thread::sleep(next - now);
+                }
+                let (inserted, rejected, failed) = summary.take();
+                info!(inserted, rejected, failed, "events since last summary");
+            }
+        });
+    }
+}
+
+#[test]
+fn summary_counts() {
+    let metrics = Metrics::default();
+    metrics.count("events.inserted", 3);
+    metrics.count("events.rejected", 1);
+    metrics.count("events.spooled", 2);
+    metrics.count("events.failed", 1);
+    metrics.count("events.received", 7);
+    assert_eq!(metrics.summary.take(), (3, 1, 3));
+    assert_eq!(metrics.summary.take(), (0, 0, 0));
+}
@@ -0,0 +1,85 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Proxies that are trusted by default when `--trust-proxy` is given: the loopback and private
+/// networks, where reverse proxies typically live.
+const DEFAULT_TRUSTED_PROXIES: &[&str] = &["127.0.0.0/8", "10.0.0.0/8", "172.16.0.0/12", "192.168.0.0/16", "::1/128", "fc00::/7"];
+
+/// Reverse proxies whose `X-Forwarded-For` headers are believed.
+#[derive(Clone, Debug)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl Default for TrustedProxies {
+    fn default() -> TrustedProxies {
+        TrustedProxies::parse(DEFAULT_TRUSTED_PROXIES.iter().cloned()).unwrap()
+    }
+}
+
+/// Parses a network in CIDR notation, like `10.0.0.0/8`. A plain IP address is taken to be a
+/// network containing only that address.
+pub fn parse_network(network: &str) -> Result<IpNet, String> {
+    let network = network.trim();
+    network.parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("invalid network {}", network))
+}
+
+impl TrustedProxies {
+    /// Parses networks as accepted by `parse_network`.
+    pub fn parse<'a, I>(networks: I) -> Result<TrustedProxies, String>
+        where I: IntoIterator<Item = &'a str>
+    {
+        let networks = networks.into_iter()
+            .map(parse_network)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(TrustedProxies { networks })
+    }
+
+    fn trusts(&self, ip: IpAddr) -> bool {
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Returns the IP address of the client, given the address that the request came from and the
+    /// values of its `X-Forwarded-For` headers. Each trusted proxy appends the address it received
+    /// the request from, so the client is the rightmost entry that is not a trusted proxy; anything
+    /// to the left of it may have been made up by the client.
+    pub fn client_ip<'a, I>(&self, remote_ip: IpAddr, forwarded_for: I) -> IpAddr
+        where I: IntoIterator<Item = &'a str>
+    {
+        let entries = forwarded_for.into_iter()
+            .flat_map(|header| header.split(','))
+            .collect::<Vec<_>>();
+        let mut client_ip = remote_ip;
+        for entry in entries.iter().rev() {
+            if !self.trusts(client_ip) {
+                break;
+            }
+            match entry.trim().parse::<IpAddr>() {
+                Ok(ip) => client_ip = ip,
+                // The proxy that added this doesn't know where the request came from, so the best
+                // we can say is that it came from that proxy.
+                Err(_) => break,
+            }
+        }
+        client_ip
+    }
+}
+
+#[test]
+fn client_ip_from_forwarded_for() {
+    let proxies = TrustedProxies::parse(vec!["10.0.0.0/8", "192.0.2.1"]).unwrap();
+    let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+    // Untrusted remotes are the client, whatever they claim.
+    assert_eq!(proxies.client_ip(ip("203.0.113.7"), vec!["198.51.100.1"]), ip("203.0.113.7"));
+    // The rightmost untrusted entry is the client; the ones to its left may be spoofed.
+    assert_eq!(proxies.client_ip(ip("10.0.0.1"), vec!["1.1.1.1, 198.51.100.1, 192.0.2.1"]), ip("198.51.100.1"));
+    assert_eq!(proxies.client_ip(ip("10.0.0.1"), vec!["1.1.1.1", "198.51.100.1"]), ip("198.51.100.1"));
+    // If all entries are trusted, the leftmost one is the best guess.
+    assert_eq!(proxies.client_ip(ip("10.0.0.1"), vec!["10.0.0.2"]), ip("10.0.0.2"));
+    assert_eq!(proxies.client_ip(ip("10.0.0.1"), vec!["unknown"]), ip("10.0.0.1"));
+    assert_eq!(proxies.client_ip(ip("10.0.0.1"), vec![]), ip("10.0.0.1"));
+    assert!(TrustedProxies::parse(vec!["10.0.0.0/33"]).is_err());
+}
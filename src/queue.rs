@@ -0,0 +1,70 @@
+use std::fmt::Display;
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use tracing::{error, warn};
+
+/// Delay before the first retry; it doubles for each subsequent retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Passes jobs to a background thread, which retries each job that fails a few times before giving
+/// up. Forwarding inserted events elsewhere is built on this, and is best effort: jobs that don't
+/// fit in the queue or keep failing are logged and dropped, but don't affect the insertion into the
+/// database.
+#[derive(Debug)]
+pub struct RetryQueue<T> {
+    name: &'static str,
+    sender: SyncSender<T>,
+}
+
+impl<T: Display + Send + 'static> RetryQueue<T> {
+    /// Creates a queue that holds at most `capacity` jobs, and starts the thread that calls `attempt`
+    /// for each of them until it succeeds, at most `max_attempts` times. `name` identifies the queue
+    /// in log messages.
+    pub fn start<E, F>(name: &'static str, capacity: usize, max_attempts: u32, mut attempt: F) -> RetryQueue<T>
+        where E: Display, F: FnMut(&T) -> Result<(), E> + Send + 'static
+    {
+        let (sender, receiver) = mpsc::sync_channel::<T>(capacity);
+        thread::spawn(move || {
+            for job in receiver {
+                let mut delay = RETRY_DELAY;
+                for attempt_number in 1..=max_attempts {
+                    match attempt(&job) {
+                        Ok(()) => break,
+                        Err(err) if attempt_number < max_attempts => {
+                            warn!(queue = name, %job, ?delay, error = %err, "job failed, retrying");
+                            thread::sleep(delay);
+                            delay *= 2;
+                        }
+                        Err(err) => error!(queue = name, %job, error = %err, "job failed, giving up"),
+                    }
+                }
+            }
+        });
+        RetryQueue { name, sender }
+    }
+
+    /// Queues the job, unless the queue is full.
+    pub fn push(&self, job: T) {
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(job)) => error!(queue = self.name, %job, "queue is full, dropping job"),
+            Err(TrySendError::Disconnected(job)) => error!(queue = self.name, %job, "queue thread has stopped, dropping job"),
+        }
+    }
+}
+
+#[test]
+fn retry_failed_jobs() {
+    let (done_sender, done_receiver) = mpsc::channel();
+    let mut attempts = 0;
+    let queue = RetryQueue::start("test", 1, 2, move |job: &String| {
+        attempts += 1;
+        done_sender.send((job.to_string(), attempts)).unwrap();
+        if attempts == 1 { Err("first attempt") } else { Ok(()) }
+    });
+    queue.push("job".to_string());
+    assert_eq!(done_receiver.recv().unwrap(), ("job".to_string(), 1));
+    assert_eq!(done_receiver.recv().unwrap(), ("job".to_string(), 2));
+}
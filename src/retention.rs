@@ -0,0 +1,44 @@
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use r2d2::Pool;
+use r2d2_postgres::PostgresConnectionManager;
+use tracing::{error, info};
+
+use crate::db::{self, DbError, Pools};
+use crate::schema::{Schema, Table};
+
+/// Spawns a background thread that removes expired events from all tables that have a retention
+/// period configured, in every database, every `interval`.
+pub fn start_retention_thread(schema: Schema, db_pools: Pools, interval: Duration) {
+    thread::spawn(move || {
+        loop {
+            for (database_url, db_conn_pool) in db_pools.all() {
+                for table in schema.for_database(database_url).tables.values() {
+                    if let Err(err) = remove_expired(table, db_conn_pool) {
+                        error!(table = %table.name, error = %err, "failed to remove expired events");
+                    }
+                }
+            }
+            thread::sleep(interval);
+        }
+    });
+}
+
+fn remove_expired(table: &Table, db_conn_pool: &Pool<PostgresConnectionManager>) -> Result<(), DbError> {
+    let retention = match &table.retention {
+        Some(retention) => retention,
+        None => return Ok(()),
+    };
+    let cutoff = Utc::now() - chrono::Duration::days(retention.days as i64);
+    let conn = db_conn_pool.get()?;
+    if table.partition_by.as_ref() == Some(&retention.column) {
+        for partition_name in db::drop_expired_partitions(table, &*conn, cutoff)? {
+            info!(table = %table.name, partition = %partition_name, "dropped expired partition");
+        }
+    }
+    let deleted = db::delete_expired_events(table, &retention.column, &*conn, cutoff)?;
+    info!(table = %table.name, deleted, "deleted expired events");
+    Ok(())
+}
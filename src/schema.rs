@@ -1,60 +1,590 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt::Display;
+use std::iter;
+use std::net::IpAddr;
 #[cfg(test)]
 use std::fs::File;
 #[cfg(test)]
 use std::io::Read;
 
-use serde::Deserialize;
+use ipnet::IpNet;
+use itertools::Itertools;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use subtle::ConstantTimeEq;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
 
-use crate::types::Type;
+use crate::db;
+use crate::proxy;
+use crate::types::{self, Type};
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Schema {
+    /// Postgres enum types, mapping each type name to its values.
+    #[serde(default)]
+    pub enums: HashMap<String, Vec<String>>,
     pub tables: HashMap<String, Table>,
     pub apps: HashMap<String, App>,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct App {
     #[serde(skip)]
     pub app_id: String,
-    pub secret_key: String,
-    #[serde(default = "default_access_control_allow_origin")]
-    pub access_control_allow_origin: String,
+    // Several keys can be given, so that a new key can be rolled out while the old one is still
+    // in use.
+    #[serde(default, rename = "secret_key", deserialize_with = "deserialize_string_or_list", serialize_with = "serialize_redacted_list")]
+    pub secret_keys: Vec<String>,
+    #[serde(default, serialize_with = "serialize_redacted_option")]
+    pub secret_key_hash: Option<String>,
+    #[serde(default)]
+    pub scoped_keys: Vec<ScopedKey>,
+    #[serde(default)]
+    pub allowed_ips: Vec<String>,
+    #[serde(skip)]
+    pub allowed_networks: Vec<IpNet>,
+    #[serde(default)]
+    pub require_signature: bool,
+    #[serde(default)]
+    pub replay_protection: Option<ReplayProtection>,
+    #[serde(default = "default_access_control_allow_origin", deserialize_with = "deserialize_string_or_list")]
+    pub access_control_allow_origin: Vec<String>,
+    #[serde(default)]
+    pub cors_max_age: Option<usize>,
+    #[serde(default)]
+    pub cors_allowed_headers: Option<Vec<String>>,
+    #[serde(default)]
+    pub webhook_url: Option<String>,
+    #[serde(default)]
+    pub table_prefix: Option<String>,
+    #[serde(default)]
+    pub default_table: Option<String>,
+    #[serde(default, serialize_with = "serialize_redacted_option")]
+    pub database_url: Option<String>,
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
     pub tables: Vec<String>,
 }
 
-fn default_access_control_allow_origin() -> String {
-    "*".to_string()
+fn default_access_control_allow_origin() -> Vec<String> {
+    vec!["*".to_string()]
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>),
+}
+
+fn deserialize_string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+    where D: Deserializer<'de>
+{
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(string) => vec![string],
+        StringOrList::List(list) => list,
+    })
+}
+
+/// Replaces secrets when the schema is serialized, so it can be shown without revealing them.
+const REDACTED: &str = "<redacted>";
+
+fn serialize_redacted<S>(_: &str, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    serializer.serialize_str(REDACTED)
+}
+
+fn serialize_redacted_list<S>(list: &[String], serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    serializer.collect_seq(list.iter().map(|_| REDACTED))
+}
+
+fn serialize_redacted_option<S>(option: &Option<String>, serializer: S) -> Result<S::Ok, S::Error>
+    where S: Serializer
+{
+    option.as_ref().map(|_| REDACTED).serialize(serializer)
+}
+
+/// A secret key that only allows writing to some of the app's tables.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ScopedKey {
+    #[serde(serialize_with = "serialize_redacted")]
+    pub key: String,
+    pub tables: Vec<String>,
+}
+
+/// The tables of an app that a secret key allows writing to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyScope<'a> {
+    AllTables,
+    Tables(&'a [String]),
+}
+
+impl KeyScope<'_> {
+    /// Returns whether the key allows writing to the given (unprefixed) table.
+    pub fn allows(&self, table_name: &str) -> bool {
+        match self {
+            KeyScope::AllTables => true,
+            KeyScope::Tables(tables) => tables.iter().any(|table| table == table_name),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct ReplayProtection {
+    #[serde(default = "default_max_clock_skew")]
+    pub max_clock_skew: u64,
+    #[serde(default = "default_nonce_cache_size")]
+    pub nonce_cache_size: usize,
+}
+
+fn default_max_clock_skew() -> u64 {
+    300
+}
+
+fn default_nonce_cache_size() -> usize {
+    10000
+}
+
+impl App {
+    /// Returns the string that is prepended to the names of the tables this app writes to.
+    pub fn table_prefix(&self) -> &str {
+        self.table_prefix.as_deref().unwrap_or("")
+    }
+
+    /// Returns the names of the tables that this app's events are actually stored in, that is,
+    /// with the `table_prefix` applied.
+    pub fn stored_table_names(&self) -> impl Iterator<Item = String> + '_ {
+        self.tables.iter().map(move |table_name| format!("{}{}", self.table_prefix(), table_name))
+    }
+
+    /// Returns whether requests from the given client IP address are accepted for this app. If it
+    /// has no `allowed_ips`, all of them are; otherwise, the address must be known.
+    pub fn allows_ip(&self, ip: Option<IpAddr>) -> bool {
+        self.allowed_networks.is_empty() || ip.map_or(false, |ip| self.allowed_networks.iter().any(|network| network.contains(&ip)))
+    }
+
+    /// Returns whether the given key matches one of the app's `secret_key`s, or its
+    /// `secret_key_hash`.
+    pub fn verify_secret_key(&self, key: &str) -> bool {
+        match &self.secret_key_hash {
+            // Every key is compared in constant time, so the timing doesn't reveal which one matched
+            // or how much of it.
+            None => self.secret_keys.iter()
+                .fold(false, |matched, secret_key| matched | bool::from(key.as_bytes().ct_eq(secret_key.as_bytes()))),
+            Some(hash) => bcrypt::verify(key, hash).unwrap_or(false),
+        }
+    }
+
+    /// Returns the tables that the given key allows writing to: all of them if it matches
+    /// `verify_secret_key`, or those listed for it in `scoped_keys`. Returns `None` if the key is
+    /// not valid for this app at all.
+    pub fn key_scope(&self, key: &str) -> Option<KeyScope> {
+        if self.verify_secret_key(key) {
+            return Some(KeyScope::AllTables);
+        }
+        // Like the secret keys, all scoped keys are compared in constant time.
+        self.scoped_keys.iter()
+            .fold(None, |matched, scoped_key| if bool::from(key.as_bytes().ct_eq(scoped_key.key.as_bytes())) {
+                Some(KeyScope::Tables(&scoped_key.tables))
+            } else {
+                matched
+            })
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Table {
     #[serde(skip)]
     pub name: String,
+    #[serde(default)]
+    pub mode: TableMode,
+    #[serde(default)]
     pub columns: Vec<Column>,
+    #[serde(default)]
+    pub id_column: bool,
+    #[serde(default)]
+    pub partition_by: Option<String>,
+    #[serde(default)]
+    pub retention: Option<Retention>,
+    #[serde(default)]
+    pub kafka_topic: Option<String>,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub sample_rate: Option<f64>,
+    #[serde(default)]
+    pub unique_key: Vec<String>,
+    /// Columns that make up the primary key, instead of the `id` of `id_column`.
+    #[serde(default)]
+    pub primary_key: Vec<String>,
+    #[serde(default)]
+    pub on_conflict: OnConflict,
+    #[serde(default)]
+    pub rollups: Vec<Rollup>,
+    #[serde(skip)]
+    pub insert_query: String,
+}
+
+/// Name of the column of a rollup table that holds the start of the time bucket.
+pub const ROLLUP_BUCKET_COLUMN: &str = "bucket";
+/// Name of the column of a rollup table that holds the number of events.
+pub const ROLLUP_COUNT_COLUMN: &str = "count";
+
+/// Counts of a table's events per time bucket and combination of values of the `group_by`
+/// columns, which are kept up to date in a separate table as events are inserted, so that
+/// dashboards don't need to aggregate the events themselves.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Rollup {
+    /// Name of the table that holds the counts.
+    pub name: String,
+    /// The timestamp column whose value is truncated to the start of its bucket.
+    pub time_column: String,
+    pub bucket: Bucket,
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    #[serde(skip)]
+    pub upsert_query: String,
+}
+
+/// Length of the time buckets of a rollup. Buckets start at whole units in UTC; weeks start on
+/// Monday.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Bucket {
+    Minute,
+    Hour,
+    Day,
+    Week,
+    Month,
 }
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+impl Bucket {
+    /// Returns the name of the unit as used by the Postgres `date_trunc` function.
+    pub fn date_trunc_field(self) -> &'static str {
+        match self {
+            Bucket::Minute => "minute",
+            Bucket::Hour => "hour",
+            Bucket::Day => "day",
+            Bucket::Week => "week",
+            Bucket::Month => "month",
+        }
+    }
+}
+
+/// What happens when an inserted event conflicts with an existing row on a unique constraint.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+    /// The insertion fails, like any other database error.
+    #[default]
+    Error,
+    /// The event is silently not inserted, so that batches can safely be sent again.
+    Ignore,
+    /// The existing row's columns outside the `unique_key`, or the `primary_key` if there is no
+    /// `unique_key`, are overwritten by the event's.
+    Update,
+}
+
+/// How events are stored in a table.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TableMode {
+    /// Each configured column is populated from the event.
+    #[default]
+    Columns,
+    /// The entire event is stored as JSON, along with an id and the time it was received, so that
+    /// no columns need to be declared. Additional columns can still be added.
+    Jsonb,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct Retention {
+    pub days: u32,
+    pub column: String,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Column {
     pub name: String,
+    #[serde(default)]
+    pub key: Option<String>,
     #[serde(rename = "type", default)]
     pub type_: Type,
     #[serde(default)]
     pub header: Option<String>,
     #[serde(default)]
+    pub source: Option<Source>,
+    #[serde(default)]
+    pub allowed_values: Option<Vec<serde_json::Value>>,
+    #[serde(default)]
+    pub default: Option<serde_json::Value>,
+    #[serde(default)]
+    pub fill_if_absent: Option<FillIfAbsent>,
+    #[serde(default)]
+    pub reject_precision_loss: bool,
+    #[serde(default)]
+    pub allow_non_finite: bool,
+    #[serde(default)]
+    pub lowercase: bool,
+    #[serde(default)]
+    pub trim: bool,
+    #[serde(default)]
+    pub encrypt: bool,
+    #[serde(default)]
     pub indexed: bool,
     #[serde(default)]
     pub required: bool,
+    #[serde(default)]
+    pub required_if: Option<RequiredIf>,
+}
+
+/// Makes a column required only in events where another column has a particular value.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RequiredIf {
+    pub column: String,
+    pub equals: serde_json::Value,
+}
+
+/// A value that is stored in a timestamp column if the event doesn't have one.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FillIfAbsent {
+    /// The time at which the server received the request.
+    Now,
+}
+
+/// Request metadata that a column can take its value from, instead of the event itself.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Source {
+    RequestMethod,
+    RequestPath,
+    ReceivedAt,
+    GeoipCountry,
+    BodySize,
+    RawEvent,
+    BatchId,
+    /// The table name that the client gave in the event, before `default_table` was applied.
+    EventType,
+    /// The (decoded) value of the query string parameter with the given name, written as
+    /// `query:<name>`.
+    Query(String),
+}
+
+impl TryFrom<String> for Source {
+    type Error = String;
+    fn try_from(name: String) -> Result<Source, String> {
+        match name.as_str() {
+            "request_method" => Ok(Source::RequestMethod),
+            "request_path" => Ok(Source::RequestPath),
+            "received_at" => Ok(Source::ReceivedAt),
+            "geoip_country" => Ok(Source::GeoipCountry),
+            "body_size" => Ok(Source::BodySize),
+            "raw_event" => Ok(Source::RawEvent),
+            "batch_id" => Ok(Source::BatchId),
+            "event_type" => Ok(Source::EventType),
+            _ => match name.strip_prefix("query:") {
+                Some(param) if !param.is_empty() && param.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') =>
+                    Ok(Source::Query(param.to_string())),
+                Some(param) => Err(format!("invalid query parameter name {:?} in source {}", param, name)),
+                None => Err(format!("unknown source {}, expected one of request_method, request_path, received_at, geoip_country, body_size, raw_event, batch_id, event_type, query:<param>", name)),
+            },
+        }
+    }
+}
+
+impl From<Source> for String {
+    fn from(source: Source) -> String {
+        match source {
+            Source::RequestMethod => "request_method".to_string(),
+            Source::RequestPath => "request_path".to_string(),
+            Source::ReceivedAt => "received_at".to_string(),
+            Source::GeoipCountry => "geoip_country".to_string(),
+            Source::BodySize => "body_size".to_string(),
+            Source::RawEvent => "raw_event".to_string(),
+            Source::BatchId => "batch_id".to_string(),
+            Source::EventType => "event_type".to_string(),
+            Source::Query(param) => format!("query:{}", param),
+        }
+    }
+}
+
+impl Source {
+    /// Returns the type that a column with this source must have.
+    pub fn column_type(&self) -> Type {
+        match self {
+            Source::RequestMethod | Source::RequestPath | Source::GeoipCountry | Source::EventType | Source::Query(_) => Type::String,
+            Source::ReceivedAt => Type::Timestamp,
+            Source::BodySize => Type::I64,
+            Source::RawEvent => Type::Jsonb,
+            Source::BatchId => Type::Uuid,
+        }
+    }
+}
+
+impl Schema {
+    /// Returns whether any table forwards its events to Kafka.
+    pub fn uses_kafka(&self) -> bool {
+        self.tables.values().any(|table| table.kafka_topic.is_some())
+    }
+
+    /// Returns the distinct `database_url`s that apps have configured, in sorted order.
+    pub fn database_urls(&self) -> Vec<&str> {
+        let mut database_urls = self.apps.values().filter_map(|app| app.database_url.as_deref()).collect::<Vec<_>>();
+        database_urls.sort();
+        database_urls.dedup();
+        database_urls
+    }
+
+    /// Returns the part of the schema that is stored in the database with the given
+    /// `database_url`: the apps that use it, and their tables. For the default database (`None`),
+    /// this also includes the tables that no app uses, so they are still created. Enums are
+    /// included everywhere.
+    pub fn for_database(&self, database_url: Option<&str>) -> Schema {
+        let apps = self.apps.iter()
+            .filter(|(_, app)| app.database_url.as_deref() == database_url)
+            .map(|(app_id, app)| (app_id.to_string(), app.clone()))
+            .collect::<HashMap<_, _>>();
+        let tables_here = apps.values().flat_map(App::stored_table_names).collect::<HashSet<_>>();
+        let tables_used = self.apps.values().flat_map(App::stored_table_names).collect::<HashSet<_>>();
+        let tables = self.tables.iter()
+            .filter(|(table_name, _)| tables_here.contains(*table_name) || (database_url.is_none() && !tables_used.contains(*table_name)))
+            .map(|(table_name, table)| (table_name.to_string(), table.clone()))
+            .collect();
+        Schema { enums: self.enums.clone(), tables, apps }
+    }
+
+    /// Returns whether any column is encrypted, which needs an encryption key.
+    pub fn uses_encryption(&self) -> bool {
+        self.tables.values()
+            .flat_map(|table| &table.columns)
+            .any(|column| column.encrypt)
+    }
+
+    /// Returns whether any column needs a GeoIP database to be populated.
+    pub fn uses_geoip(&self) -> bool {
+        self.tables.values()
+            .flat_map(|table| &table.columns)
+            .any(|column| column.source == Some(Source::GeoipCountry))
+    }
+}
+
+impl Table {
+    /// Returns the columns whose values identify a row for `on_conflict: update`.
+    pub fn conflict_key(&self) -> &[String] {
+        if self.unique_key.is_empty() { &self.primary_key } else { &self.unique_key }
+    }
+
+    /// Returns the columns that a table in `jsonb` mode gets in addition to the configured ones.
+    fn jsonb_mode_columns() -> Vec<Column> {
+        let column = |name: &str, type_: Type, source: Source| Column {
+            name: name.to_string(),
+            key: None,
+            type_,
+            header: None,
+            source: Some(source),
+            allowed_values: None,
+            default: None,
+            fill_if_absent: None,
+            reject_precision_loss: false,
+            allow_non_finite: false,
+            lowercase: false,
+            trim: false,
+            encrypt: false,
+            indexed: false,
+            required: true,
+            required_if: None,
+        };
+        vec![
+            column("received_at", Type::Timestamp, Source::ReceivedAt),
+            column("payload", Type::Jsonb, Source::RawEvent),
+        ]
+    }
+}
+
+impl Column {
+    /// Returns the key that this column's value is read from in the event JSON.
+    pub fn json_key(&self) -> &str {
+        self.key.as_ref().unwrap_or(&self.name)
+    }
+
+    /// Returns the type of the column in Postgres. Encrypted values are stored as bytes.
+    pub fn postgres_type_name(&self) -> String {
+        if self.encrypt {
+            "BYTEA".to_string()
+        } else {
+            self.type_.postgres_type_name()
+        }
+    }
+
+    /// Returns whether every event for the table must contain a value for this column, because it
+    /// is required and its value isn't filled in any other way.
+    pub fn must_be_sent(&self) -> bool {
+        self.required && self.default.is_none() && self.fill_if_absent.is_none() && self.header.is_none() && self.source.is_none()
+    }
+
+    /// Applies the column's `trim` and `lowercase` options to a string value from an event. Other
+    /// values are returned as they are.
+    pub fn normalize<'a>(&self, json: &'a serde_json::Value) -> Cow<'a, serde_json::Value> {
+        match json {
+            serde_json::Value::String(s) if self.trim || self.lowercase => {
+                let s = if self.trim { s.trim() } else { s };
+                Cow::Owned(serde_json::Value::String(if self.lowercase { s.to_lowercase() } else { s.to_string() }))
+            }
+            _ => Cow::Borrowed(json),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum SchemaError {
     YamlParseError(serde_yaml::Error),
+    DuplicateKey { key: String, line: usize },
+    InvalidName { kind: &'static str, name: String },
     TableNotFound { app_id: String, table_name: String },
-    WrongColumnType { actual: Type, expected: Type },
+    DefaultTableNotListed { app_id: String, table_name: String },
+    ScopedKeyTableNotListed { app_id: String, table_name: String },
+    SecretKeyConflict { app_id: String },
+    SecretKeyMissing { app_id: String },
+    InvalidSecretKeyHash { app_id: String, err: bcrypt::BcryptError },
+    SignatureRequiresSecretKey { app_id: String },
+    InvalidWebhookUrl { app_id: String, err: url::ParseError },
+    InvalidAllowedIps { app_id: String, err: String },
+    WrongColumnType { table_name: String, column_name: String, actual: Type, expected: Type },
+    ConflictingColumnSources { table_name: String, column_name: String },
+    InvalidAllowedValues { table_name: String, column_name: String },
+    InvalidColumnValue { table_name: String, column_name: String, value: serde_json::Value },
+    InvalidDefault { table_name: String, column_name: String },
+    DefaultNotAllowed { table_name: String, column_name: String, value: serde_json::Value },
+    InvalidPrecisionCheck { table_name: String, column_name: String },
+    InvalidNonFinite { table_name: String, column_name: String },
+    InvalidNormalization { table_name: String, column_name: String },
+    InvalidFillIfAbsent { table_name: String, column_name: String },
+    InvalidEncryption { table_name: String, column_name: String },
+    InvalidRequiredIf { table_name: String, column_name: String, condition_column: String },
+    EnumNotFound { table_name: String, column_name: String, enum_name: String },
+    EmptyEnum { enum_name: String },
+    IdColumnConflict { table_name: String },
+    ReservedColumnName { table_name: String, column_name: String },
+    InvalidSampleRate { table_name: String, sample_rate: f64 },
+    PrefixedTableConflict { app_id: String, table_name: String },
+    InvalidPartitionColumn { table_name: String, column_name: String },
+    InvalidRetentionColumn { table_name: String, column_name: String },
+    InvalidUniqueKey { table_name: String, column_name: String },
+    InvalidPrimaryKey { table_name: String, column_name: String },
+    PrimaryKeyWithIdColumn { table_name: String },
+    UpdateWithoutUniqueKey { table_name: String },
+    InvalidRollupTimeColumn { table_name: String, rollup_name: String, column_name: String },
+    InvalidRollupGroupColumn { table_name: String, rollup_name: String, column_name: String },
+    RollupTableConflict { table_name: String, rollup_name: String },
+    RollupWithUpdate { table_name: String },
+    MaxBodySizeTooLarge { app_id: String, max_body_size: usize, limit: u64 },
 }
 
 impl Display for SchemaError {
@@ -62,37 +592,560 @@ impl Display for SchemaError {
         match self {
             SchemaError::YamlParseError(err) =>
                 write!(f, "{}", err),
+            SchemaError::DuplicateKey {key, line} =>
+                write!(f, "key {} at line {} occurs more than once in the same mapping", key, line),
+            SchemaError::InvalidName {kind, name} =>
+                write!(f, "{} name {:?} must be 1 to {} bytes long and must not contain double quotes", kind, name, MAX_NAME_LENGTH),
+            SchemaError::DefaultTableNotListed {app_id, table_name} =>
+                write!(f, "app {} has default_table {}, which is not in its list of tables", app_id, table_name),
+            SchemaError::ScopedKeyTableNotListed {app_id, table_name} =>
+                write!(f, "app {} has a scoped key for table {}, which is not in its list of tables", app_id, table_name),
             SchemaError::TableNotFound {app_id, table_name} =>
                 write!(f, "app {} refers to undefined table {}", app_id, table_name),
-            SchemaError::WrongColumnType {actual, expected} =>
-                write!(f, "column type should be {:?} here, but was {:?}", expected, actual),
+            SchemaError::SecretKeyConflict {app_id} =>
+                write!(f, "app {} has both secret_key and secret_key_hash", app_id),
+            SchemaError::SecretKeyMissing {app_id} =>
+                write!(f, "app {} has neither secret_key nor secret_key_hash", app_id),
+            SchemaError::InvalidSecretKeyHash {app_id, err} =>
+                write!(f, "app {} has an invalid secret_key_hash: {}", app_id, err),
+            SchemaError::InvalidWebhookUrl {app_id, err} =>
+                write!(f, "app {} has an invalid webhook_url: {}", app_id, err),
+            SchemaError::InvalidAllowedIps {app_id, err} =>
+                write!(f, "app {} has invalid allowed_ips: {}", app_id, err),
+            SchemaError::SignatureRequiresSecretKey {app_id} =>
+                write!(f, "app {} requires a signature, which needs secret_key rather than secret_key_hash", app_id),
+            SchemaError::WrongColumnType {table_name, column_name, actual, expected} =>
+                write!(f, "column {} in table {} is populated from a header or source, so its type should be {:?}, but was {:?}", column_name, table_name, expected, actual),
+            SchemaError::ConflictingColumnSources {table_name, column_name} =>
+                write!(f, "column {} in table {} has both a header and a source", column_name, table_name),
+            SchemaError::InvalidAllowedValues {table_name, column_name} =>
+                write!(f, "column {} in table {} has allowed_values, but it has a header or source", column_name, table_name),
+            SchemaError::InvalidDefault {table_name, column_name} =>
+                write!(f, "column {} in table {} has a default, but it has a header or source", column_name, table_name),
+            SchemaError::DefaultNotAllowed {table_name, column_name, value} =>
+                write!(f, "column {} in table {} has default {}, which is not one of its allowed_values", column_name, table_name, value),
+            SchemaError::InvalidColumnValue {table_name, column_name, value} =>
+                write!(f, "column {} in table {} lists value {}, which is not valid for its type", column_name, table_name, value),
+            SchemaError::InvalidPrecisionCheck {table_name, column_name} =>
+                write!(f, "column {} in table {} has reject_precision_loss, which is only supported for f32 columns", column_name, table_name),
+            SchemaError::InvalidNonFinite {table_name, column_name} =>
+                write!(f, "column {} in table {} has allow_non_finite, which is only supported for f32 and f64 columns", column_name, table_name),
+            SchemaError::InvalidNormalization {table_name, column_name} =>
+                write!(f, "column {} in table {} has lowercase or trim, which is only supported for string columns without a header or source", column_name, table_name),
+            SchemaError::InvalidFillIfAbsent {table_name, column_name} =>
+                write!(f, "column {} in table {} has fill_if_absent, which is only supported for timestamp columns without a header or source", column_name, table_name),
+            SchemaError::InvalidEncryption {table_name, column_name} =>
+                write!(f, "column {} in table {} has encrypt, which is only supported for string columns without a header, source, allowed_values, default, indexed, unique_key or primary_key", column_name, table_name),
+            SchemaError::InvalidRequiredIf {table_name, column_name, condition_column} =>
+                write!(f, "column {} in table {} is required_if column {}, which is not a column in the table without a header or source", column_name, table_name, condition_column),
+            SchemaError::EnumNotFound {table_name, column_name, enum_name} =>
+                write!(f, "column {} in table {} refers to undefined enum {}", column_name, table_name, enum_name),
+            SchemaError::EmptyEnum {enum_name} =>
+                write!(f, "enum {} has no values", enum_name),
+            SchemaError::PrefixedTableConflict {app_id, table_name} =>
+                write!(f, "app {} writes to table {} because of its table_prefix, but that table is also defined separately", app_id, table_name),
+            SchemaError::ReservedColumnName {table_name, column_name} =>
+                write!(f, "table {} is in jsonb mode, which reserves the column name {}", table_name, column_name),
+            SchemaError::IdColumnConflict {table_name} =>
+                write!(f, "table {} has id_column, but also a column named {}", table_name, db::ID_COLUMN_NAME),
+            SchemaError::InvalidSampleRate {table_name, sample_rate} =>
+                write!(f, "table {} has sample_rate {}, which is not between 0 and 1", table_name, sample_rate),
+            SchemaError::InvalidPartitionColumn {table_name, column_name} =>
+                write!(f, "table {} is partitioned by {}, which is not a required timestamp column in the table", table_name, column_name),
+            SchemaError::InvalidRetentionColumn {table_name, column_name} =>
+                write!(f, "table {} has retention based on {}, which is not a timestamp column in the table", table_name, column_name),
+            SchemaError::InvalidUniqueKey {table_name, column_name} =>
+                write!(f, "table {} has {} in its unique_key, which is not a column in the table", table_name, column_name),
+            SchemaError::InvalidPrimaryKey {table_name, column_name} =>
+                write!(f, "table {} has {} in its primary_key, which is not a required column in the table", table_name, column_name),
+            SchemaError::PrimaryKeyWithIdColumn {table_name} =>
+                write!(f, "table {} has both a primary_key and id_column, which is the primary key itself", table_name),
+            SchemaError::UpdateWithoutUniqueKey {table_name} =>
+                write!(f, "table {} has on_conflict: update, which requires a unique_key or primary_key", table_name),
+            SchemaError::InvalidRollupTimeColumn {table_name, rollup_name, column_name} =>
+                write!(f, "rollup {} of table {} has time_column {}, which is not a required timestamp column in the table", rollup_name, table_name, column_name),
+            SchemaError::InvalidRollupGroupColumn {table_name, rollup_name, column_name} =>
+                write!(f, "rollup {} of table {} groups by {}, which is not a required unencrypted column in the table, or is named {} or {}",
+                       rollup_name, table_name, column_name, ROLLUP_BUCKET_COLUMN, ROLLUP_COUNT_COLUMN),
+            SchemaError::RollupTableConflict {table_name, rollup_name} =>
+                write!(f, "rollup {} of table {} has the same name as another table or rollup", rollup_name, table_name),
+            SchemaError::RollupWithUpdate {table_name} =>
+                write!(f, "table {} has on_conflict: update, which can't be combined with rollups", table_name),
+            SchemaError::MaxBodySizeTooLarge {app_id, max_body_size, limit} =>
+                write!(f, "app {} has max_body_size {}, which is larger than the server-wide limit of {} bytes", app_id, max_body_size, limit),
         }
     }
 }
 
 impl Error for SchemaError {}
 
+/// Something in the schema that is allowed, but probably a mistake.
+#[derive(Debug)]
+pub enum SchemaWarning {
+    SharedSecretKey { app_id: String, other_app_id: String },
+}
+
+impl Display for SchemaWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            SchemaWarning::SharedSecretKey {app_id, other_app_id} =>
+                write!(f, "apps {} and {} have the same secret_key", other_app_id, app_id),
+        }
+    }
+}
+
 impl Schema {
     pub fn from_yaml(yaml_str: &str) -> Result<Schema, SchemaError> {
+        let (schema, mut errors) = Schema::load(yaml_str)?;
+        if errors.is_empty() {
+            Ok(schema)
+        } else {
+            Err(errors.remove(0))
+        }
+    }
+
+    /// Checks the schema like `from_yaml`, but returns all problems with it rather than only the
+    /// first, so that they can be fixed in one go. Each column, table and app contributes at most
+    /// one problem, because later checks on it may depend on earlier ones.
+    /// The warnings of a schema that could be loaded are returned as well. `max_body_size` is the
+    /// server-wide limit, as for `check_max_body_size`.
+    pub fn lint(yaml_str: &str, max_body_size: u64) -> (Vec<SchemaError>, Vec<SchemaWarning>) {
+        match Schema::load(yaml_str) {
+            Ok((schema, mut errors)) => {
+                errors.extend(schema.check_max_body_size(max_body_size));
+                (errors, schema.warnings())
+            }
+            Err(err) => (vec![err], Vec::new()),
+        }
+    }
+
+    /// Checks that no app has a `max_body_size` above the server-wide limit on the body size, which
+    /// is given on the command line. Such a value would have no effect, because larger bodies are
+    /// rejected before the app's limit is checked.
+    pub fn check_max_body_size(&self, limit: u64) -> Vec<SchemaError> {
+        self.apps.values()
+            .sorted_by_key(|app| &app.app_id)
+            .filter_map(|app| match app.max_body_size {
+                Some(max_body_size) if max_body_size as u64 > limit =>
+                    Some(SchemaError::MaxBodySizeTooLarge { app_id: app.app_id.to_string(), max_body_size, limit }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns the things in the schema that are allowed but probably not intended.
+    pub fn warnings(&self) -> Vec<SchemaWarning> {
+        let mut warnings = Vec::new();
+        let mut apps_by_secret_key = HashMap::new();
+        for app_id in self.apps.keys().sorted() {
+            for secret_key in &self.apps[app_id].secret_keys {
+                if let Some(other_app_id) = apps_by_secret_key.insert(secret_key, app_id) {
+                    warnings.push(SchemaWarning::SharedSecretKey { app_id: app_id.to_string(), other_app_id: other_app_id.to_string() });
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Parses the schema and prepares it for use. Returns an error only if the YAML could not be
+    /// parsed; other problems are returned along with the schema, which must not be used then.
+    fn load(yaml_str: &str) -> Result<(Schema, Vec<SchemaError>), SchemaError> {
         let mut schema = serde_yaml::from_str::<Schema>(yaml_str)
             .map_err(|err| SchemaError::YamlParseError(err))?;
-        for (table_name, table) in &mut schema.tables {
-            table.name = table_name.to_string();
-            for column in &mut table.columns {
-                if column.header.is_some() && column.type_ != Type::String {
-                    return Err(SchemaError::WrongColumnType { actual: column.type_.clone(), expected: Type::String })
+        let mut errors = Vec::new();
+        errors.extend(find_duplicate_keys(yaml_str).into_iter().map(|(key, line)| SchemaError::DuplicateKey { key, line }));
+        let mut enum_names = schema.enums.keys().collect::<Vec<_>>();
+        enum_names.sort();
+        for enum_name in enum_names {
+            if let Err(err) = check_name("enum", enum_name) {
+                errors.push(err);
+            } else if schema.enums[enum_name].is_empty() {
+                errors.push(SchemaError::EmptyEnum { enum_name: enum_name.to_string() });
+            }
+        }
+        let mut table_names = schema.tables.keys().cloned().collect::<Vec<_>>();
+        table_names.sort();
+        for table_name in table_names {
+            if let Err(err) = check_name("table", &table_name) {
+                errors.push(err);
+                continue;
+            }
+            let table = schema.tables.get_mut(&table_name).unwrap();
+            table.name = table_name;
+            if let Err(err) = table.prepare() {
+                errors.push(err);
+                continue;
+            }
+            let table = &*table;
+            errors.extend(table.columns.iter().filter_map(|column| check_column(&schema.enums, table, column).err()));
+            errors.extend(check_table(table).err());
+        }
+        let mut app_ids = schema.apps.keys().cloned().collect::<Vec<_>>();
+        app_ids.sort();
+        for app_id in app_ids {
+            let app = schema.apps.get_mut(&app_id).unwrap();
+            app.app_id = app_id;
+            errors.extend(prepare_app(&schema.tables, app).err());
+        }
+        // Each app with a table_prefix gets its own copy of each of its tables, under the prefixed
+        // name. Unprefixed tables are still created as usual.
+        let mut prefixed_tables = HashMap::<String, (String, Table)>::new();
+        for (app_id, app) in &schema.apps {
+            let table_prefix = match &app.table_prefix {
+                Some(table_prefix) => table_prefix,
+                None => continue,
+            };
+            // Tables that don't exist have been reported above.
+            for table in app.tables.iter().filter_map(|table_name| schema.tables.get(table_name)) {
+                let mut table = table.clone();
+                table.name = format!("{}{}", table_prefix, table.name);
+                for rollup in &mut table.rollups {
+                    rollup.name = format!("{}{}", table_prefix, rollup.name);
+                }
+                table.prepare_queries();
+                // The prefix can make a valid name too long.
+                let names = check_name("table", &table.name)
+                    .and_then(|_| table.rollups.iter().try_for_each(|rollup| check_name("rollup", &rollup.name)));
+                if let Err(err) = names {
+                    errors.push(err);
+                    continue;
+                }
+                let conflicts = schema.tables.contains_key(&table.name) || prefixed_tables.get(&table.name)
+                    .map_or(false, |(other_app_id, other_table)| other_app_id != app_id && *other_table != table);
+                if conflicts {
+                    errors.push(SchemaError::PrefixedTableConflict { app_id: app_id.to_string(), table_name: table.name });
+                    continue;
+                }
+                prefixed_tables.insert(table.name.clone(), (app_id.to_string(), table));
+            }
+        }
+        schema.tables.extend(prefixed_tables.into_iter().map(|(name, (_, table))| (name, table)));
+
+        let mut rollup_names = HashSet::new();
+        for table in schema.tables.values().sorted_by_key(|table| &table.name) {
+            for rollup in &table.rollups {
+                if schema.tables.contains_key(&rollup.name) || !rollup_names.insert(&rollup.name) {
+                    errors.push(SchemaError::RollupTableConflict { table_name: table.name.to_string(), rollup_name: rollup.name.to_string() });
                 }
             }
         }
-        for (app_id, app) in &mut schema.apps {
-            app.app_id = app_id.to_string();
-            for table_name in &app.tables {
-                if !schema.tables.contains_key(table_name) {
-                    return Err(SchemaError::TableNotFound {app_id: app_id.to_string(), table_name: table_name.to_string()})
+        Ok((schema, errors))
+    }
+}
+
+impl Table {
+    /// Adds the columns of `jsonb` mode and computes the insertion query. `name` must have been
+    /// set.
+    fn prepare(&mut self) -> Result<(), SchemaError> {
+        if self.mode == TableMode::Jsonb {
+            self.id_column = true;
+            for jsonb_column in Table::jsonb_mode_columns() {
+                // A schema that was serialized after loading already contains these columns.
+                match self.columns.iter().find(|column| column.name == jsonb_column.name) {
+                    Some(column) if *column == jsonb_column => {}
+                    Some(_) => return Err(SchemaError::ReservedColumnName { table_name: self.name.to_string(), column_name: jsonb_column.name }),
+                    None => self.columns.push(jsonb_column),
                 }
             }
         }
-        Ok(schema)
+        self.prepare_queries();
+        Ok(())
+    }
+
+    /// Computes the queries that insert events into the table and update its rollups, after its
+    /// name or the names of its rollups have changed.
+    fn prepare_queries(&mut self) {
+        self.insert_query = db::insertion_query(self);
+        let upsert_queries = self.rollups.iter().map(|rollup| db::rollup_upsert_query(self, rollup)).collect::<Vec<_>>();
+        for (rollup, upsert_query) in self.rollups.iter_mut().zip(upsert_queries) {
+            rollup.upsert_query = upsert_query;
+        }
+    }
+
+    /// Returns the tables that hold the counts of the table's rollups. Each has a `bucket`
+    /// column, the `group_by` columns with the same types as in this table, and a `count`.
+    pub fn rollup_tables(&self) -> Vec<Table> {
+        self.rollups.iter()
+            .map(|rollup| {
+                let group_columns = rollup.group_by.iter()
+                    .filter_map(|column_name| self.columns.iter().find(|column| &column.name == column_name))
+                    .map(|column| Column { name: column.name.to_string(), type_: column.type_.clone(), required: true, ..Column::default() });
+                let columns = iter::once(Column { name: ROLLUP_BUCKET_COLUMN.to_string(), type_: Type::Timestamp, required: true, ..Column::default() })
+                    .chain(group_columns)
+                    .chain(iter::once(Column { name: ROLLUP_COUNT_COLUMN.to_string(), type_: Type::I64, required: true, ..Column::default() }))
+                    .collect();
+                Table {
+                    name: rollup.name.to_string(),
+                    columns,
+                    primary_key: iter::once(ROLLUP_BUCKET_COLUMN.to_string()).chain(rollup.group_by.iter().cloned()).collect(),
+                    ..Table::default()
+                }
+            })
+            .collect()
+    }
+}
+
+/// Checks that the column's options are consistent with its type and with each other.
+fn check_column(enums: &HashMap<String, Vec<String>>, table: &Table, column: &Column) -> Result<(), SchemaError> {
+    let table_name = &table.name;
+    check_name("column", &column.name)?;
+    if column.header.is_some() && column.source.is_some() {
+        return Err(SchemaError::ConflictingColumnSources { table_name: table_name.to_string(), column_name: column.name.to_string() })
+    }
+    let expected_type = match (&column.header, &column.source) {
+        (Some(_), _) => Some(Type::String),
+        (None, source) => source.as_ref().map(Source::column_type),
+    };
+    // The raw event can also be stored as JSON text.
+    let raw_event_text = column.source == Some(Source::RawEvent) && column.type_ == Type::String;
+    if let Some(expected_type) = expected_type.filter(|_| !raw_event_text) {
+        if column.type_ != expected_type {
+            return Err(SchemaError::WrongColumnType {
+                table_name: table_name.to_string(),
+                column_name: column.name.to_string(),
+                actual: column.type_.clone(),
+                expected: expected_type,
+            })
+        }
+    }
+    if let Type::Enum(enum_name) = &column.type_ {
+        if !enums.contains_key(enum_name) {
+            return Err(SchemaError::EnumNotFound {
+                table_name: table_name.to_string(),
+                column_name: column.name.to_string(),
+                enum_name: enum_name.to_string(),
+            })
+        }
+    }
+    if column.reject_precision_loss && column.type_ != Type::F32 {
+        return Err(SchemaError::InvalidPrecisionCheck { table_name: table_name.to_string(), column_name: column.name.to_string() })
+    }
+    if column.allow_non_finite && column.type_ != Type::F32 && column.type_ != Type::F64 {
+        return Err(SchemaError::InvalidNonFinite { table_name: table_name.to_string(), column_name: column.name.to_string() })
+    }
+    if column.lowercase || column.trim {
+        let string_type = match column.type_ {
+            Type::String | Type::Char(_) | Type::Enum(_) => true,
+            _ => false,
+        };
+        if !string_type || column.header.is_some() || column.source.is_some() {
+            return Err(SchemaError::InvalidNormalization { table_name: table_name.to_string(), column_name: column.name.to_string() })
+        }
+    }
+    // Encrypted values can't be compared in the database, so nothing may depend on that.
+    if column.encrypt && (column.type_ != Type::String || column.header.is_some() || column.source.is_some() ||
+                          column.allowed_values.is_some() || column.default.is_some() || column.indexed ||
+                          table.unique_key.contains(&column.name) || table.primary_key.contains(&column.name)) {
+        return Err(SchemaError::InvalidEncryption { table_name: table_name.to_string(), column_name: column.name.to_string() })
+    }
+    if column.fill_if_absent.is_some() && (column.type_ != Type::Timestamp || column.header.is_some() || column.source.is_some()) {
+        return Err(SchemaError::InvalidFillIfAbsent { table_name: table_name.to_string(), column_name: column.name.to_string() })
+    }
+    if let Some(allowed_values) = &column.allowed_values {
+        if column.header.is_some() || column.source.is_some() {
+            return Err(SchemaError::InvalidAllowedValues { table_name: table_name.to_string(), column_name: column.name.to_string() })
+        }
+        check_name("constraint", &db::check_constraint_name(table, column))?;
+        for value in allowed_values {
+            check_literal(enums, table_name, column, value)?;
+        }
+    }
+    if let Some(default) = &column.default {
+        if column.header.is_some() || column.source.is_some() {
+            return Err(SchemaError::InvalidDefault { table_name: table_name.to_string(), column_name: column.name.to_string() })
+        }
+        check_literal(enums, table_name, column, default)?;
+        if column.allowed_values.as_ref().map_or(false, |allowed_values| !types::is_allowed(allowed_values, default)) {
+            return Err(SchemaError::DefaultNotAllowed { table_name: table_name.to_string(), column_name: column.name.to_string(), value: default.clone() })
+        }
+    }
+    if let Some(required_if) = &column.required_if {
+        let condition_column = table.columns.iter()
+            .find(|other| other.name == required_if.column && other.header.is_none() && other.source.is_none())
+            .ok_or_else(|| SchemaError::InvalidRequiredIf {
+                table_name: table_name.to_string(),
+                column_name: column.name.to_string(),
+                condition_column: required_if.column.to_string(),
+            })?;
+        check_literal(enums, table_name, condition_column, &required_if.equals)?;
+    }
+    Ok(())
+}
+
+/// Checks the table-level options, such as the columns that they refer to.
+fn check_table(table: &Table) -> Result<(), SchemaError> {
+    let table_name = &table.name;
+    if table.id_column && table.columns.iter().any(|column| column.name == db::ID_COLUMN_NAME) {
+        return Err(SchemaError::IdColumnConflict { table_name: table_name.to_string() })
+    }
+    if let Some(sample_rate) = table.sample_rate {
+        if !(0.0..=1.0).contains(&sample_rate) {
+            return Err(SchemaError::InvalidSampleRate { table_name: table_name.to_string(), sample_rate })
+        }
+    }
+    if let Some(partition_by) = &table.partition_by {
+        let valid = table.columns.iter().any(|column|
+            &column.name == partition_by && column.type_ == Type::Timestamp && column.required && column.header.is_none() && column.source.is_none());
+        if !valid {
+            return Err(SchemaError::InvalidPartitionColumn { table_name: table_name.to_string(), column_name: partition_by.to_string() })
+        }
+    }
+    if let Some(retention) = &table.retention {
+        if !table.columns.iter().any(|column| column.name == retention.column && column.type_ == Type::Timestamp) {
+            return Err(SchemaError::InvalidRetentionColumn { table_name: table_name.to_string(), column_name: retention.column.to_string() })
+        }
+    }
+    for key_column in &table.unique_key {
+        if !table.columns.iter().any(|column| &column.name == key_column) {
+            return Err(SchemaError::InvalidUniqueKey { table_name: table_name.to_string(), column_name: key_column.to_string() })
+        }
+    }
+    for key_column in &table.primary_key {
+        if !table.columns.iter().any(|column| &column.name == key_column && column.required) {
+            return Err(SchemaError::InvalidPrimaryKey { table_name: table_name.to_string(), column_name: key_column.to_string() })
+        }
+    }
+    if table.id_column && !table.primary_key.is_empty() {
+        return Err(SchemaError::PrimaryKeyWithIdColumn { table_name: table_name.to_string() })
+    }
+    if table.on_conflict == OnConflict::Update && table.conflict_key().is_empty() {
+        return Err(SchemaError::UpdateWithoutUniqueKey { table_name: table_name.to_string() })
+    }
+    // An updated row can't be told apart from an inserted one, so it would be counted again.
+    if table.on_conflict == OnConflict::Update && !table.rollups.is_empty() {
+        return Err(SchemaError::RollupWithUpdate { table_name: table_name.to_string() })
+    }
+    for rollup in &table.rollups {
+        check_name("rollup", &rollup.name)?;
+        if !table.columns.iter().any(|column| column.name == rollup.time_column && column.type_ == Type::Timestamp && column.required) {
+            return Err(SchemaError::InvalidRollupTimeColumn {
+                table_name: table_name.to_string(),
+                rollup_name: rollup.name.to_string(),
+                column_name: rollup.time_column.to_string(),
+            })
+        }
+        for group_column in &rollup.group_by {
+            let valid = group_column != ROLLUP_BUCKET_COLUMN && group_column != ROLLUP_COUNT_COLUMN &&
+                table.columns.iter().any(|column| &column.name == group_column && column.required && !column.encrypt);
+            if !valid {
+                return Err(SchemaError::InvalidRollupGroupColumn {
+                    table_name: table_name.to_string(),
+                    rollup_name: rollup.name.to_string(),
+                    column_name: group_column.to_string(),
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Checks the app's settings, and parses its `allowed_ips`. `app_id` must have been set.
+fn prepare_app(tables: &HashMap<String, Table>, app: &mut App) -> Result<(), SchemaError> {
+    let app_id = &app.app_id;
+    match (app.secret_keys.is_empty(), &app.secret_key_hash) {
+        (false, Some(_)) => return Err(SchemaError::SecretKeyConflict {app_id: app_id.to_string()}),
+        (true, None) => return Err(SchemaError::SecretKeyMissing {app_id: app_id.to_string()}),
+        (true, Some(hash)) => {
+            bcrypt::verify("", hash)
+                .map_err(|err| SchemaError::InvalidSecretKeyHash {app_id: app_id.to_string(), err})?;
+        }
+        (false, None) => {}
+    }
+    if app.require_signature && app.secret_keys.is_empty() {
+        return Err(SchemaError::SignatureRequiresSecretKey {app_id: app_id.to_string()})
+    }
+    app.allowed_networks = app.allowed_ips.iter()
+        .map(|network| proxy::parse_network(network))
+        .collect::<Result<_, _>>()
+        .map_err(|err| SchemaError::InvalidAllowedIps {app_id: app_id.to_string(), err})?;
+    if let Some(webhook_url) = &app.webhook_url {
+        url::Url::parse(webhook_url)
+            .map_err(|err| SchemaError::InvalidWebhookUrl {app_id: app_id.to_string(), err})?;
+    }
+    for table_name in &app.tables {
+        if !tables.contains_key(table_name) {
+            return Err(SchemaError::TableNotFound {app_id: app_id.to_string(), table_name: table_name.to_string()})
+        }
+    }
+    if let Some(default_table) = &app.default_table {
+        if !app.tables.contains(default_table) {
+            return Err(SchemaError::DefaultTableNotListed {app_id: app_id.to_string(), table_name: default_table.to_string()})
+        }
+    }
+    for table_name in app.scoped_keys.iter().flat_map(|scoped_key| &scoped_key.tables) {
+        if !app.tables.contains(table_name) {
+            return Err(SchemaError::ScopedKeyTableNotListed {app_id: app_id.to_string(), table_name: table_name.to_string()})
+        }
+    }
+    Ok(())
+}
+
+/// Maximum length in bytes of a name in Postgres. Longer names are silently truncated, so they
+/// would not match when the table is checked or written to.
+const MAX_NAME_LENGTH: usize = 63;
+
+/// Checks that a name can be used in SQL as a quoted identifier, as it is.
+fn check_name(kind: &'static str, name: &str) -> Result<(), SchemaError> {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH || name.contains('"') {
+        return Err(SchemaError::InvalidName { kind, name: name.to_string() })
+    }
+    Ok(())
+}
+
+/// Checks that a value given in the schema for a column could actually be stored in it, so that
+/// mistakes show up when loading the schema rather than as events that can never be inserted.
+fn check_literal(enums: &HashMap<String, Vec<String>>, table_name: &str, column: &Column, value: &serde_json::Value) -> Result<(), SchemaError> {
+    let valid = column.type_.accepts(value) && match &column.type_ {
+        Type::F32 => value.as_f64().map_or(false, |f| f.abs() <= f64::from(f32::MAX)),
+        Type::Enum(enum_name) => enums.get(enum_name).map_or(false, |values| values.iter().any(|v| Some(v.as_str()) == value.as_str())),
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(SchemaError::InvalidColumnValue { table_name: table_name.to_string(), column_name: column.name.to_string(), value: value.clone() })
+    }
+}
+
+/// Returns each key that occurs again in the same mapping, along with the line number where it
+/// does. Serde would otherwise silently keep only the last of them, so that for example a
+/// duplicated app would override the first one.
+fn find_duplicate_keys(yaml_str: &str) -> Vec<(String, usize)> {
+    let mut finder = DuplicateKeyFinder { stack: Vec::new(), duplicates: Vec::new() };
+    // Syntax errors have already been reported by serde_yaml.
+    match Parser::new(yaml_str.chars()).load(&mut finder, true) {
+        Ok(()) => finder.duplicates,
+        Err(_) => Vec::new(),
+    }
+}
+
+enum Collection {
+    Mapping { keys: HashSet<String>, expecting_key: bool },
+    Sequence,
+}
+
+struct DuplicateKeyFinder {
+    stack: Vec<Collection>,
+    duplicates: Vec<(String, usize)>,
+}
+
+impl MarkedEventReceiver for DuplicateKeyFinder {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        let is_node = matches!(event, Event::Scalar(..) | Event::Alias(_) | Event::SequenceStart(_) | Event::MappingStart(_));
+        if let (true, Some(Collection::Mapping { keys, expecting_key })) = (is_node, self.stack.last_mut()) {
+            if let (true, Event::Scalar(key, ..)) = (*expecting_key, &event) {
+                if !keys.insert(key.to_string()) {
+                    self.duplicates.push((key.to_string(), mark.line()));
+                }
+            }
+            *expecting_key = !*expecting_key;
+        }
+        match event {
+            Event::MappingStart(_) => self.stack.push(Collection::Mapping { keys: HashSet::new(), expecting_key: true }),
+            Event::SequenceStart(_) => self.stack.push(Collection::Sequence),
+            Event::MappingEnd | Event::SequenceEnd => { self.stack.pop(); }
+            _ => {}
+        }
     }
 }
 
@@ -103,70 +1156,850 @@ fn parse_example_schema() {
     file.read_to_string(&mut contents).unwrap();
     let schema = Schema::from_yaml(&contents).unwrap();
     let expected_schema = Schema {
+        enums: HashMap::new(),
         tables: [
             ("events".to_string(), Table {
                 name: "events".to_string(),
                 columns: vec![
                     Column {
                         name: "time".to_string(),
+                        key: None,
                         type_: Type::Timestamp,
                         header: None,
+                        source: None,
+                        allowed_values: None,
+                        default: None,
+                        fill_if_absent: None,
+                        reject_precision_loss: false,
+                        allow_non_finite: false,
+                        lowercase: false,
+                        trim: false,
+                        encrypt: false,
                         indexed: true,
                         required: false,
+                        required_if: None,
                     },
                     Column {
                         name: "referer".to_string(),
+                        key: None,
                         type_: Type::String,
                         header: Some("Referer".to_string()),
+                        source: None,
+                        allowed_values: None,
+                        default: None,
+                        fill_if_absent: None,
+                        reject_precision_loss: false,
+                        allow_non_finite: false,
+                        lowercase: false,
+                        trim: false,
+                        encrypt: false,
                         indexed: false,
                         required: false,
+                        required_if: None,
                     },
                     Column {
                         name: "platform".to_string(),
+                        key: None,
                         type_: Type::String,
                         header: None,
+                        source: None,
+                        allowed_values: None,
+                        default: None,
+                        fill_if_absent: None,
+                        reject_precision_loss: false,
+                        allow_non_finite: false,
+                        lowercase: false,
+                        trim: false,
+                        encrypt: false,
                         indexed: true,
                         required: true,
+                        required_if: None,
                     },
                     Column {
                         name: "version".to_string(),
+                        key: None,
                         type_: Type::String,
                         header: None,
+                        source: None,
+                        allowed_values: None,
+                        default: None,
+                        fill_if_absent: None,
+                        reject_precision_loss: false,
+                        allow_non_finite: false,
+                        lowercase: false,
+                        trim: false,
+                        encrypt: false,
                         indexed: true,
                         required: true,
+                        required_if: None,
                     },
                     Column {
                         name: "user_id".to_string(),
+                        key: None,
                         type_: Type::String,
                         header: None,
+                        source: None,
+                        allowed_values: None,
+                        default: None,
+                        fill_if_absent: None,
+                        reject_precision_loss: false,
+                        allow_non_finite: false,
+                        lowercase: false,
+                        trim: false,
+                        encrypt: false,
                         indexed: false,
                         required: false,
+                        required_if: None,
                     },
                     Column {
                         name: "event_type".to_string(),
+                        key: None,
                         type_: Type::String,
                         header: None,
+                        source: None,
+                        allowed_values: None,
+                        default: None,
+                        fill_if_absent: None,
+                        reject_precision_loss: false,
+                        allow_non_finite: false,
+                        lowercase: false,
+                        trim: false,
+                        encrypt: false,
                         indexed: true,
                         required: true,
+                        required_if: None,
                     },
                     Column {
                         name: "score".to_string(),
+                        key: None,
                         type_: Type::I32,
                         header: None,
+                        source: None,
+                        allowed_values: None,
+                        default: None,
+                        fill_if_absent: None,
+                        reject_precision_loss: false,
+                        allow_non_finite: false,
+                        lowercase: false,
+                        trim: false,
+                        encrypt: false,
                         indexed: false,
                         required: false,
+                        required_if: None,
                     }
                 ],
+                id_column: false,
+                partition_by: None,
+                retention: None,
+                kafka_topic: None,
+                strict: false,
+                mode: TableMode::Columns,
+                sample_rate: None,
+                unique_key: vec![],
+                primary_key: vec![],
+                on_conflict: OnConflict::Error,
+                rollups: vec![],
+                insert_query: r#"INSERT INTO "events" ("time", "referer", "platform", "version", "user_id", "event_type", "score") VALUES ($1, $2, $3, $4, $5, $6, $7)"#.to_string(),
             }),
         ].iter().cloned().collect(),
         apps: [
             ("com.example.myapp".to_string(), App {
                 app_id: "com.example.myapp".to_string(),
-                secret_key: "qD3eRda0709mD/3kGp4DlJtEQy5aMY0m".to_string(),
-                access_control_allow_origin: "http://example.com".to_string(),
+                secret_keys: vec!["qD3eRda0709mD/3kGp4DlJtEQy5aMY0m".to_string()],
+                secret_key_hash: None,
+                scoped_keys: vec![],
+                allowed_ips: vec![],
+                allowed_networks: vec![],
+                require_signature: false,
+                replay_protection: None,
+                access_control_allow_origin: vec!["http://example.com".to_string()],
+                cors_max_age: None,
+                cors_allowed_headers: None,
+                webhook_url: None,
+                default_table: None,
+                table_prefix: None,
+                database_url: None,
+                max_body_size: None,
                 tables: vec!["events".to_string()],
             }),
         ].iter().cloned().collect(),
     };
     assert_eq!(schema, expected_schema);
 }
+
+#[test]
+fn example_schema_round_trips() {
+    let schema = Schema::from_yaml(&std::fs::read_to_string("schema-example.conf.yaml").unwrap()).unwrap();
+    let reparsed = Schema::from_yaml(&serde_yaml::to_string(&schema).unwrap()).unwrap();
+    assert_eq!(reparsed.tables, schema.tables);
+    // Secret keys are redacted when serializing, so they can't survive the round trip.
+    let without_secret_keys = |schema: &Schema| schema.apps.values()
+        .map(|app| App { secret_keys: Vec::new(), ..app.clone() })
+        .collect::<Vec<_>>();
+    assert_eq!(without_secret_keys(&reparsed), without_secret_keys(&schema));
+}
+
+#[test]
+fn parse_access_control_allow_origin_list() {
+    let schema = Schema::from_yaml(r#"
+        tables: {}
+        apps:
+          app:
+            secret_key: secret
+            access_control_allow_origin:
+              - http://example.com
+              - https://example.org
+            tables: []
+        "#).unwrap();
+    assert_eq!(schema.apps["app"].access_control_allow_origin,
+               vec!["http://example.com".to_string(), "https://example.org".to_string()]);
+}
+
+#[test]
+fn verify_secret_key_hash() {
+    let schema = Schema::from_yaml(&format!(r#"
+        tables: {{}}
+        apps:
+          app:
+            secret_key_hash: "{}"
+            tables: []
+        "#, bcrypt::hash("secret", 4).unwrap())).unwrap();
+    assert!(schema.apps["app"].verify_secret_key("secret"));
+    assert!(!schema.apps["app"].verify_secret_key("public"));
+}
+
+#[test]
+fn verify_one_of_several_secret_keys() {
+    let schema = Schema::from_yaml(r#"
+        tables: {}
+        apps:
+          app:
+            secret_key: [old, new]
+            tables: []
+        "#).unwrap();
+    assert!(schema.apps["app"].verify_secret_key("old"));
+    assert!(schema.apps["app"].verify_secret_key("new"));
+    assert!(!schema.apps["app"].verify_secret_key("other"));
+}
+
+#[test]
+fn scoped_keys_limit_tables() {
+    let schema = |tables: &str| Schema::from_yaml(&format!(r#"
+        tables: {{a: {{}}, b: {{}}}}
+        apps:
+          app:
+            secret_key: secret
+            scoped_keys:
+              - key: only_a
+                tables: {}
+            tables: [a, b]
+        "#, tables));
+    let app = &schema("[a]").unwrap().apps["app"];
+    assert_eq!(app.key_scope("secret"), Some(KeyScope::AllTables));
+    let scope = app.key_scope("only_a").unwrap();
+    assert!(scope.allows("a"));
+    assert!(!scope.allows("b"));
+    assert_eq!(app.key_scope("other"), None);
+    assert!(!serde_yaml::to_string(app).unwrap().contains("only_a"));
+    match schema("[c]") {
+        Err(SchemaError::ScopedKeyTableNotListed {ref table_name, ..}) if table_name == "c" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn split_schema_by_database() {
+    let schema = Schema::from_yaml(r#"
+        tables: {shared: {}, own: {}, unused: {}}
+        apps:
+          a:
+            secret_key: a
+            tables: [shared]
+          b:
+            secret_key: b
+            database_url: postgres://b
+            tables: [shared, own]
+        "#).unwrap();
+    assert_eq!(schema.database_urls(), vec!["postgres://b"]);
+    let table_names = |database_url: Option<&str>| {
+        let mut table_names = schema.for_database(database_url).tables.keys().cloned().collect::<Vec<_>>();
+        table_names.sort();
+        table_names
+    };
+    assert_eq!(table_names(None), vec!["shared", "unused"]);
+    assert_eq!(table_names(Some("postgres://b")), vec!["own", "shared"]);
+    assert_eq!(schema.for_database(Some("postgres://b")).apps.keys().collect::<Vec<_>>(), vec!["b"]);
+}
+
+#[test]
+fn allowed_ips_of_app() {
+    let schema = |allowed_ips: &str| Schema::from_yaml(&format!(r#"
+        tables: {{}}
+        apps:
+          app:
+            secret_key: secret
+            allowed_ips: {}
+            tables: []
+        "#, allowed_ips));
+    let ip = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+    let app = &schema("[]").unwrap().apps["app"];
+    assert!(app.allows_ip(ip("203.0.113.7")));
+    assert!(app.allows_ip(None));
+    let app = &schema("[10.0.0.0/8, 2001:db8::1]").unwrap().apps["app"];
+    assert!(app.allows_ip(ip("10.1.2.3")));
+    assert!(app.allows_ip(ip("2001:db8::1")));
+    assert!(!app.allows_ip(ip("203.0.113.7")));
+    assert!(!app.allows_ip(None));
+    match schema("[10.0.0.0/33]") {
+        Err(SchemaError::InvalidAllowedIps {..}) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn reject_secret_key_and_hash() {
+    let result = Schema::from_yaml(r#"
+        tables: {}
+        apps:
+          app:
+            secret_key: secret
+            secret_key_hash: "$2b$04$Zf1cBfkVvGqXu0dCiHlGDeCPb5Y0sN0sBoh2qMqRz/zIqjPSHPL2S"
+            tables: []
+        "#);
+    match result {
+        Err(SchemaError::SecretKeyConflict {app_id}) => assert_eq!(app_id, "app"),
+        _ => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn parse_request_sources() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: method
+                source: request_method
+              - name: path
+                source: request_path
+              - name: received_at
+                type: timestamp
+                source: received_at
+              - name: body_size
+                type: i64
+                source: body_size
+        apps: {}
+        "#).unwrap();
+    let sources = schema.tables["events"].columns.iter().map(|column| column.source.clone()).collect::<Vec<_>>();
+    assert_eq!(sources, vec![Some(Source::RequestMethod), Some(Source::RequestPath), Some(Source::ReceivedAt), Some(Source::BodySize)]);
+
+    let result = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: path
+                type: i32
+                source: request_path
+        apps: {}
+        "#);
+    match result {
+        Err(SchemaError::WrongColumnType {ref table_name, ref column_name, ..}) if table_name == "events" && column_name == "path" => {}
+        _ => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn raw_event_column_types() {
+    let schema = |type_: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns:
+              - name: payload
+                type: {}
+                source: raw_event
+        apps: {{}}
+        "#, type_));
+    assert!(schema("jsonb").is_ok());
+    assert!(schema("string").is_ok());
+    match schema("i64") {
+        Err(SchemaError::WrongColumnType {expected: Type::Jsonb, ..}) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn parse_query_source() {
+    let schema = |source: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns:
+              - name: campaign
+                source: "{}"
+        apps: {{}}
+        "#, source));
+    assert_eq!(schema("query:utm_campaign").unwrap().tables["events"].columns[0].source,
+               Some(Source::Query("utm_campaign".to_string())));
+    for source in &["query:", "query:a&b", "querystring"] {
+        assert!(schema(source).is_err(), "source {} should be invalid", source);
+    }
+    assert_eq!(String::from(Source::Query("ref".to_string())), "query:ref");
+}
+
+#[test]
+fn header_column_must_be_string() {
+    let result = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: referer
+                type: i64
+                header: Referer
+        apps: {}
+        "#);
+    match result {
+        Err(SchemaError::WrongColumnType {ref table_name, ref column_name, actual: Type::I64, expected: Type::String})
+            if table_name == "events" && column_name == "referer" => {}
+        _ => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn column_json_key_defaults_to_name() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: uid
+                key: user_id
+              - name: platform
+        apps: {}
+        "#).unwrap();
+    let keys = schema.tables["events"].columns.iter().map(Column::json_key).collect::<Vec<_>>();
+    assert_eq!(keys, vec!["user_id", "platform"]);
+}
+
+#[test]
+fn prefixed_tables() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: platform
+        apps:
+          app:
+            secret_key: key
+            table_prefix: app_
+            tables: [events]
+        "#).unwrap();
+    let table = &schema.tables["app_events"];
+    assert_eq!(table.name, "app_events");
+    assert!(table.insert_query.starts_with(r#"INSERT INTO "app_events" "#));
+    assert!(schema.tables.contains_key("events"));
+}
+
+#[test]
+fn reject_duplicate_keys() {
+    let result = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns: []
+        apps:
+          app:
+            secret_key: first
+            tables: [events]
+          app:
+            secret_key: second
+            tables: []
+        "#);
+    match result {
+        Err(SchemaError::DuplicateKey {ref key, line: 9}) if key == "app" => {}
+        _ => panic!("unexpected result: {:?}", result),
+    }
+    let (errors, _) = Schema::lint(r#"
+        tables:
+          events: {columns: []}
+          events: {columns: []}
+        apps:
+          app: {secret_key: first, tables: []}
+          app: {secret_key: second, tables: []}
+        "#, 32 * 1024);
+    let duplicates = errors.iter()
+        .map(|err| match err {
+            SchemaError::DuplicateKey {key, line} => (key.as_str(), *line),
+            err => panic!("unexpected error: {}", err),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(duplicates, vec![("events", 4), ("app", 7)]);
+}
+
+#[test]
+fn enum_column_requires_declared_enum() {
+    let schema = |enums: &str| Schema::from_yaml(&format!(r#"
+        enums: {}
+        tables:
+          events:
+            columns:
+              - name: platform
+                type: enum(platform_type)
+        apps: {{}}
+        "#, enums));
+    assert_eq!(schema("{platform_type: [ios, android]}").unwrap().tables["events"].columns[0].type_,
+               Type::Enum("platform_type".to_string()));
+    match schema("{}") {
+        Err(SchemaError::EnumNotFound {ref enum_name, ..}) if enum_name == "platform_type" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn allowed_values_must_fit_column_type() {
+    let schema = |type_: &str, allowed_values: &str| Schema::from_yaml(&format!(r#"
+        enums: {{platform_type: [ios, android]}}
+        tables:
+          events:
+            columns:
+              - name: value
+                type: {}
+                allowed_values: {}
+        apps: {{}}
+        "#, type_, allowed_values));
+    assert!(schema("i32", "[1, 2]").is_ok());
+    assert!(schema("enum(platform_type)", "[ios]").is_ok());
+    for (type_, allowed_values, invalid) in &[
+        ("i32", "[1, 3000000000]", serde_json::json!(3000000000u32)),
+        ("f32", "[1.5, 1e300]", serde_json::json!(1e300)),
+        ("enum(platform_type)", "[ios, windows]", serde_json::json!("windows")),
+    ] {
+        match schema(type_, allowed_values) {
+            Err(SchemaError::InvalidColumnValue {ref value, ..}) if value == invalid => {}
+            result => panic!("unexpected result for {}: {:?}", type_, result),
+        }
+    }
+}
+
+#[test]
+fn default_must_be_allowed_value() {
+    let schema = |default: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns:
+              - name: platform
+                allowed_values: [android, ios]
+                default: {}
+        apps: {{}}
+        "#, default));
+    assert!(schema("ios").is_ok());
+    match schema("web") {
+        Err(SchemaError::DefaultNotAllowed {ref column_name, ref value, ..}) if column_name == "platform" && value == "web" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn normalization_requires_string_column() {
+    let schema = |column: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns:
+              - {}
+        apps: {{}}
+        "#, column));
+    assert!(schema("{name: platform, lowercase: true, trim: true}").is_ok());
+    assert!(schema("{name: platform, type: char(8), trim: true}").is_ok());
+    for column in &["{name: level, type: i32, lowercase: true}", "{name: agent, header: User-Agent, trim: true}"] {
+        match schema(column) {
+            Err(SchemaError::InvalidNormalization {..}) => {}
+            result => panic!("unexpected result for {}: {:?}", column, result),
+        }
+    }
+}
+
+#[test]
+fn encryption_requires_plain_string_column() {
+    let schema = |column: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns:
+              - {}
+        apps: {{}}
+        "#, column));
+    let schema_ok = schema("{name: user_id, encrypt: true}").unwrap();
+    assert!(schema_ok.uses_encryption());
+    assert_eq!(schema_ok.tables["events"].columns[0].postgres_type_name(), "BYTEA");
+    for column in &[
+        "{name: level, type: i32, encrypt: true}",
+        "{name: agent, header: User-Agent, encrypt: true}",
+        "{name: user_id, indexed: true, encrypt: true}",
+        "{name: user_id, default: anonymous, encrypt: true}",
+    ] {
+        match schema(column) {
+            Err(SchemaError::InvalidEncryption {..}) => {}
+            result => panic!("unexpected result for {}: {:?}", column, result),
+        }
+    }
+}
+
+#[test]
+fn fill_if_absent_requires_timestamp_column() {
+    let schema = |column: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns:
+              - {}
+        apps: {{}}
+        "#, column));
+    assert_eq!(schema("{name: time, type: timestamp, fill_if_absent: now}").unwrap().tables["events"].columns[0].fill_if_absent,
+               Some(FillIfAbsent::Now));
+    for column in &["{name: level, type: i32, fill_if_absent: now}", "{name: time, type: timestamp, source: received_at, fill_if_absent: now}"] {
+        match schema(column) {
+            Err(SchemaError::InvalidFillIfAbsent {..}) => {}
+            result => panic!("unexpected result for {}: {:?}", column, result),
+        }
+    }
+}
+
+#[test]
+fn required_if_refers_to_event_column() {
+    let schema = |required_if: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns:
+              - name: event_type
+              - name: level
+                type: i32
+              - name: price
+                required_if: {}
+              - name: agent
+                header: User-Agent
+        apps: {{}}
+        "#, required_if));
+    assert!(schema("{column: event_type, equals: purchase}").is_ok());
+    match schema("{column: agent, equals: curl}") {
+        Err(SchemaError::InvalidRequiredIf {ref condition_column, ..}) if condition_column == "agent" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match schema("{column: level, equals: high}") {
+        Err(SchemaError::InvalidColumnValue {ref column_name, ..}) if column_name == "level" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn on_conflict_update_requires_unique_key() {
+    let schema = |options: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns: [{{name: event_id}}, {{name: score, type: i32}}]
+            {}
+        apps: {{}}
+        "#, options));
+    assert_eq!(schema("on_conflict: ignore").unwrap().tables["events"].on_conflict, OnConflict::Ignore);
+    assert!(schema("unique_key: [event_id]\n            on_conflict: update").is_ok());
+    match schema("on_conflict: update") {
+        Err(SchemaError::UpdateWithoutUniqueKey {..}) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+    match schema("unique_key: [id]") {
+        Err(SchemaError::InvalidUniqueKey {ref column_name, ..}) if column_name == "id" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn rollups_with_on_conflict() {
+    let schema = |on_conflict: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            unique_key: [event_id]
+            on_conflict: {}
+            columns: [{{name: event_id, required: true}}, {{name: time, type: timestamp, required: true}}]
+            rollups: [{{name: events_daily, time_column: time, bucket: day, group_by: []}}]
+        apps: {{}}
+        "#, on_conflict));
+    assert!(schema("ignore").unwrap().tables["events"].insert_query.ends_with(" ON CONFLICT DO NOTHING"));
+    match schema("update") {
+        Err(SchemaError::RollupWithUpdate {ref table_name}) if table_name == "events" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn primary_key_columns() {
+    let schema = |options: &str| Schema::from_yaml(&format!(r#"
+        tables:
+          events:
+            columns: [{{name: user_id, required: true}}, {{name: time, type: timestamp, required: true}}, {{name: score, type: i32}}]
+            {}
+        apps: {{}}
+        "#, options));
+    let table = &schema("primary_key: [user_id, time]\n            on_conflict: update").unwrap().tables["events"];
+    assert_eq!(table.conflict_key(), &["user_id".to_string(), "time".to_string()][..]);
+    for key_column in &["score", "level"] {
+        match schema(&format!("primary_key: [user_id, {}]", key_column)) {
+            Err(SchemaError::InvalidPrimaryKey {ref column_name, ..}) if column_name == key_column => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+    match schema("primary_key: [user_id]\n            id_column: true") {
+        Err(SchemaError::PrimaryKeyWithIdColumn {..}) => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn lint_reports_all_problems() {
+    let (errors, _) = Schema::lint(r#"
+        enums: {empty: []}
+        tables:
+          events:
+            sample_rate: 2
+            columns:
+              - name: referer
+                header: Referer
+                type: i64
+              - name: platform
+                type: enum(platforms)
+                required: true
+        apps:
+          app:
+            secret_key: secret
+            tables: [events, other]
+        "#, 32 * 1024);
+    let messages = errors.iter().map(|err| err.to_string()).collect::<Vec<_>>();
+    assert_eq!(errors.len(), 5, "{:?}", messages);
+    match &errors[..] {
+        [SchemaError::EmptyEnum {..}, SchemaError::WrongColumnType {..}, SchemaError::EnumNotFound {..},
+         SchemaError::InvalidSampleRate {..}, SchemaError::TableNotFound {..}] => {}
+        _ => panic!("unexpected errors: {:?}", messages),
+    }
+    let (errors, warnings) = Schema::lint(&std::fs::read_to_string("schema-example.conf.yaml").unwrap(), 32 * 1024);
+    assert!(errors.is_empty() && warnings.is_empty());
+    assert_eq!(Schema::lint("tables: [", 32 * 1024).0.len(), 1);
+}
+
+#[test]
+fn max_body_size_within_limit() {
+    let yaml = r#"
+        tables: {}
+        apps:
+          small: {secret_key: a, max_body_size: 1024, tables: []}
+          large: {secret_key: b, max_body_size: 65536, tables: []}
+          unlimited: {secret_key: c, tables: []}
+        "#;
+    let schema = Schema::from_yaml(yaml).unwrap();
+    assert!(schema.check_max_body_size(65536).is_empty());
+    match &schema.check_max_body_size(32 * 1024)[..] {
+        [SchemaError::MaxBodySizeTooLarge {app_id, max_body_size: 65536, limit: 32768}] if app_id == "large" => {}
+        errors => panic!("unexpected errors: {:?}", errors),
+    }
+    assert_eq!(Schema::lint(yaml, 1024).0.len(), 1);
+}
+
+#[test]
+fn shared_secret_key_warning() {
+    let schema = Schema::from_yaml(r#"
+        tables: {}
+        apps:
+          a: {secret_key: shared, tables: []}
+          b: {secret_key: [own, shared], tables: []}
+          c: {secret_key: other, tables: []}
+        "#).unwrap();
+    let warnings = schema.warnings().iter().map(|warning| warning.to_string()).collect::<Vec<_>>();
+    assert_eq!(warnings, vec!["apps a and b have the same secret_key".to_string()]);
+}
+
+#[test]
+fn invalid_names() {
+    let long_name = "t".repeat(MAX_NAME_LENGTH);
+    let (errors, _) = Schema::lint(&format!(r#"
+        enums: {{'quoted"enum': [a]}}
+        tables:
+          {}:
+            columns: [{{name: time, type: timestamp, required: true}}]
+          events:
+            columns:
+              - name: 'user"id'
+              - name: time
+                type: timestamp
+                required: true
+            rollups: [{{name: '', time_column: time, bucket: day}}]
+        apps:
+          app:
+            secret_key: secret
+            table_prefix: app_
+            tables: [{}]
+        "#, long_name, long_name), 32 * 1024);
+    let names = errors.iter()
+        .map(|err| match err {
+            SchemaError::InvalidName {kind, name} => format!("{} {}", kind, name),
+            err => panic!("unexpected error: {}", err),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec![
+        "enum quoted\"enum".to_string(),
+        r#"column user"id"#.to_string(),
+        "rollup ".to_string(),
+        format!("table app_{}", long_name),
+    ]);
+}
+
+#[test]
+fn jsonb_mode_table() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            mode: jsonb
+        apps: {}
+        "#).unwrap();
+    let table = &schema.tables["events"];
+    assert!(table.id_column);
+    let columns = table.columns.iter().map(|column| (column.name.as_str(), column.source.clone())).collect::<Vec<_>>();
+    assert_eq!(columns, vec![("received_at", Some(Source::ReceivedAt)), ("payload", Some(Source::RawEvent))]);
+    let reloaded = Schema::from_yaml(&serde_yaml::to_string(&schema).unwrap()).unwrap();
+    assert_eq!(reloaded.tables["events"], *table);
+
+    let result = Schema::from_yaml(r#"
+        tables:
+          events:
+            mode: jsonb
+            columns:
+              - name: payload
+        apps: {}
+        "#);
+    match result {
+        Err(SchemaError::ReservedColumnName {ref column_name, ..}) if column_name == "payload" => {}
+        result => panic!("unexpected result: {:?}", result),
+    }
+}
+
+#[test]
+fn columns_that_must_be_sent() {
+    let schema = Schema::from_yaml(r#"
+        tables:
+          events:
+            columns:
+              - name: user_id
+                required: true
+              - name: platform
+                required: true
+                default: web
+              - name: time
+                type: timestamp
+                required: true
+                fill_if_absent: now
+              - name: agent
+                header: User-Agent
+                required: true
+              - name: method
+                source: request_method
+                required: true
+              - name: level
+                type: i32
+        apps: {}
+        "#).unwrap();
+    let columns = schema.tables["events"].columns.iter()
+        .filter(|column| column.must_be_sent())
+        .map(|column| column.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(columns, vec!["user_id"]);
+}
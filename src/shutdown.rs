@@ -0,0 +1,101 @@
+use std::io;
+use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{info, warn};
+
+use crate::telemetry::Telemetry;
+
+/// Keeps track of requests that are being handled, so that the server can wait for them to finish
+/// before exiting. Rocket 0.4 has no way to stop accepting connections, so once shutdown has
+/// started, new requests are turned away instead.
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    state: Mutex<State>,
+    idle: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    draining: bool,
+    in_flight: usize,
+}
+
+/// Marks a request as being handled for as long as it is alive.
+#[derive(Debug)]
+pub struct InFlight<'a> {
+    shutdown: &'a Shutdown,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        let mut state = self.shutdown.state.lock().unwrap();
+        state.in_flight -= 1;
+        if state.in_flight == 0 {
+            self.shutdown.idle.notify_all();
+        }
+    }
+}
+
+impl Shutdown {
+    /// Registers the start of a request, or returns `None` if the server is shutting down.
+    pub fn start_request(&self) -> Option<InFlight> {
+        let mut state = self.state.lock().unwrap();
+        if state.draining {
+            return None;
+        }
+        state.in_flight += 1;
+        Some(InFlight { shutdown: self })
+    }
+
+    /// Stops new requests from starting, and waits for the ones in flight to finish, up to the
+    /// given timeout. Returns the number of requests that were still unfinished.
+    pub fn drain(&self, timeout: Duration) -> usize {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        state.draining = true;
+        while state.in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.idle.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        state.in_flight
+    }
+
+    /// Spawns a thread that, on SIGTERM or SIGINT, drains requests and then exits the process.
+    pub fn start_signal_thread(shutdown: Arc<Shutdown>, drain_timeout: Duration) -> Result<(), io::Error> {
+        let mut signals = Signals::new([SIGTERM, SIGINT])?;
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                info!(signal, "shutting down");
+                match shutdown.drain(drain_timeout) {
+                    0 => info!("all requests finished"),
+                    unfinished => warn!(unfinished, "drain timeout expired, abandoning unfinished requests"),
+                }
+                Telemetry::flush();
+                process::exit(0);
+            }
+        });
+        Ok(())
+    }
+}
+
+#[test]
+fn drain_waits_for_requests() {
+    let shutdown = Arc::new(Shutdown::default());
+    let in_flight = shutdown.start_request().unwrap();
+    assert_eq!(shutdown.drain(Duration::from_millis(10)), 1);
+    assert!(shutdown.start_request().is_none());
+
+    let thread_shutdown = shutdown.clone();
+    let waiter = thread::spawn(move || thread_shutdown.drain(Duration::from_secs(10)));
+    thread::sleep(Duration::from_millis(10));
+    drop(in_flight);
+    assert_eq!(waiter.join().unwrap(), 0);
+}
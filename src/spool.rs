@@ -0,0 +1,252 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use rocket::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::archive::Archive;
+use crate::db::{self, Pools, PreparedEvent, RequestMetadata};
+use crate::encryption::Encryption;
+use crate::geoip::GeoIp;
+use crate::kafka::Kafka;
+use crate::webhook::Webhooks;
+use crate::schema::Schema;
+
+/// How often the replay thread checks for spooled events.
+const REPLAY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// File that incoming batches are appended to.
+const SPOOL_FILE_NAME: &str = "spool.wal";
+/// File that batches are moved into while they are being replayed.
+const REPLAY_FILE_NAME: &str = "replay.wal";
+
+#[derive(Debug)]
+pub enum SpoolError {
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+}
+
+impl Display for SpoolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
+        match self {
+            SpoolError::IoError(err) => write!(f, "{}", err),
+            SpoolError::JsonError(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Error for SpoolError {}
+
+impl From<io::Error> for SpoolError {
+    fn from(err: io::Error) -> SpoolError {
+        SpoolError::IoError(err)
+    }
+}
+
+impl From<serde_json::Error> for SpoolError {
+    fn from(err: serde_json::Error) -> SpoolError {
+        SpoolError::JsonError(err)
+    }
+}
+
+/// One batch of events as received in a single request, along with the request metadata so that
+/// header- and request-sourced columns can be populated on replay. Stored as a single line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+struct SpooledBatch {
+    #[serde(default)]
+    app_id: Option<String>,
+    #[serde(default)]
+    method: String,
+    #[serde(default)]
+    path: String,
+    headers: Vec<(String, String)>,
+    // Batches spooled before this was recorded get the time of replay instead.
+    #[serde(default = "Utc::now")]
+    received_at: DateTime<Utc>,
+    #[serde(default)]
+    client_ip: Option<IpAddr>,
+    #[serde(default)]
+    body_size: Option<u64>,
+    #[serde(default)]
+    batch_id: Option<String>,
+    #[serde(default)]
+    query: Option<String>,
+    events: Vec<serde_json::Value>,
+}
+
+/// An append-only on-disk queue of event batches that could not be inserted because the database
+/// was unavailable. Batches are replayed in order, each in its own transaction. Delivery is
+/// at-least-once: if the server dies between committing a replayed batch and removing it from the
+/// queue, it will be inserted again.
+#[derive(Debug)]
+pub struct Spool {
+    dir: PathBuf,
+    geoip: Option<Arc<GeoIp>>,
+    encryption: Option<Arc<Encryption>>,
+    kafka: Option<Arc<Kafka>>,
+    webhooks: Arc<Webhooks>,
+    archive: Option<Arc<Archive>>,
+    // Held while appending to or rotating the spool file.
+    lock: Mutex<()>,
+}
+
+impl Spool {
+    pub fn new(dir: PathBuf, geoip: Option<Arc<GeoIp>>, encryption: Option<Arc<Encryption>>, kafka: Option<Arc<Kafka>>, webhooks: Arc<Webhooks>,
+               archive: Option<Arc<Archive>>)
+        -> Result<Spool, SpoolError>
+    {
+        fs::create_dir_all(&dir)?;
+        Ok(Spool { dir, geoip, encryption, kafka, webhooks, archive, lock: Mutex::new(()) })
+    }
+
+    pub fn append<'a, I>(&self, app_id: &str, events: I, metadata: &RequestMetadata) -> Result<(), SpoolError>
+        where I: IntoIterator<Item = &'a serde_json::Value>
+    {
+        let batch = SpooledBatch {
+            app_id: Some(app_id.to_string()),
+            method: metadata.method.to_string(),
+            path: metadata.path.to_string(),
+            headers: metadata.headers.iter().map(|header| (header.name().to_string(), header.value().to_string())).collect(),
+            received_at: metadata.received_at,
+            client_ip: metadata.client_ip,
+            body_size: metadata.body_size,
+            batch_id: metadata.batch_id.map(str::to_string),
+            query: metadata.query.map(str::to_string),
+            events: events.into_iter().cloned().collect(),
+        };
+        let mut line = serde_json::to_string(&batch)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new().create(true).append(true).open(self.dir.join(SPOOL_FILE_NAME))?;
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Spawns a background thread that periodically replays spooled batches into the database.
+    pub fn start_replay_thread(spool: Arc<Spool>, schema: Schema, db_pools: Pools) {
+        thread::spawn(move || {
+            loop {
+                if let Err(err) = spool.replay(&schema, &db_pools) {
+                    error!(error = %err, "failed to replay spooled events");
+                }
+                thread::sleep(REPLAY_INTERVAL);
+            }
+        });
+    }
+
+    fn replay(&self, schema: &Schema, db_pools: &Pools) -> Result<(), SpoolError> {
+        let spool_path = self.dir.join(SPOOL_FILE_NAME);
+        let replay_path = self.dir.join(REPLAY_FILE_NAME);
+
+        // Only take new batches once the previous replay file has been fully processed, so that
+        // ordering is preserved.
+        if !replay_path.exists() {
+            let _guard = self.lock.lock().unwrap();
+            if !spool_path.exists() {
+                return Ok(());
+            }
+            fs::rename(&spool_path, &replay_path)?;
+        }
+
+        let lines = BufReader::new(File::open(&replay_path)?)
+            .lines()
+            .collect::<Result<Vec<String>, io::Error>>()?;
+        let mut replayed = 0;
+        for (idx, line) in lines.iter().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let batch = match serde_json::from_str::<SpooledBatch>(line) {
+                Ok(batch) => batch,
+                Err(err) => {
+                    warn!(error = %err, "discarding unreadable spooled batch");
+                    continue;
+                }
+            };
+            let span = tracing::info_span!("replay", app_id = batch.app_id.as_deref().unwrap_or(""));
+            let _enter = span.enter();
+            let mut headers = HeaderMap::new();
+            for (name, value) in batch.headers {
+                headers.add_raw(name, value);
+            }
+            let metadata = RequestMetadata {
+                method: &batch.method,
+                path: &batch.path,
+                headers: &headers,
+                received_at: batch.received_at,
+                client_ip: batch.client_ip,
+                geoip: self.geoip.as_deref(),
+                encryption: self.encryption.as_deref(),
+                body_size: batch.body_size,
+                batch_id: batch.batch_id.as_deref(),
+                query: batch.query.as_deref(),
+            };
+            let app = batch.app_id.as_ref().and_then(|app_id| schema.apps.get(app_id));
+            let table_prefix = app.map_or("", |app| app.table_prefix());
+            let db_conn_pool = app.map_or(&db_pools.default, |app| db_pools.for_app(app));
+            // If the database is still unavailable, this is a connection error, so the remaining
+            // batches are kept for the next attempt.
+            let result = batch.events.iter()
+                .map(|event| PreparedEvent::new(schema, table_prefix, event, &metadata))
+                .collect::<Result<Vec<_>, _>>()
+                .and_then(|events| db::insert_events(&*db_conn_pool.get()?, &events));
+            match result {
+                Ok(written) => {
+                    // Events that were skipped as duplicates are not passed on again.
+                    let written_events = batch.events.iter().zip(written)
+                        .filter(|(_, written)| *written)
+                        .map(|(event, _)| event)
+                        .collect::<Vec<_>>();
+                    for &event in &written_events {
+                        if let Some(kafka) = &self.kafka {
+                            kafka.publish(schema, event);
+                        }
+                        if let Some(app) = app {
+                            self.webhooks.send(app, event);
+                        }
+                    }
+                    if let (Some(archive), Some(app_id), Some(batch_id)) = (&self.archive, &batch.app_id, &batch.batch_id) {
+                        archive.send(app_id, batch_id, batch.received_at, written_events.into_iter().cloned().collect());
+                    }
+                    replayed += batch.events.len();
+                }
+                Err(ref err) if err.is_connection_error() => {
+                    warn!(replayed, error = %err, "database unavailable, stopping replay of spooled events");
+                    return self.keep_remaining(&lines[idx..]);
+                }
+                Err(err) => {
+                    error!(error = %err, "discarding spooled batch that cannot be inserted");
+                }
+            }
+        }
+        fs::remove_file(&replay_path)?;
+        if replayed > 0 {
+            info!(replayed, "replayed spooled events");
+        }
+        Ok(())
+    }
+
+    /// Replaces the replay file by one containing only the given lines.
+    fn keep_remaining(&self, lines: &[String]) -> Result<(), SpoolError> {
+        let replay_path = self.dir.join(REPLAY_FILE_NAME);
+        let tmp_path = self.dir.join(format!("{}.tmp", REPLAY_FILE_NAME));
+        let mut file = File::create(&tmp_path)?;
+        for line in lines {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_data()?;
+        fs::rename(&tmp_path, &replay_path)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::TraceError;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::Tracer;
+use rocket::http::HeaderMap;
+use tokio::runtime::Runtime;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+/// Exports tracing spans to an OpenTelemetry collector over OTLP/HTTP. Spans are sent in batches
+/// from a background runtime, so requests don't wait for the collector. The runtime must be kept
+/// alive for as long as spans are being exported.
+pub struct Telemetry {
+    pub tracer: Tracer,
+    _runtime: Runtime,
+}
+
+impl Telemetry {
+    /// Sets up the exporter to the collector at `endpoint`, e.g. `http://localhost:4318`, and
+    /// installs it as the global tracer provider. Incoming `traceparent` headers are understood
+    /// from then on.
+    pub fn new(endpoint: &str) -> Result<Telemetry, TraceError> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("otlp-export")
+            .enable_all()
+            .build()
+            .map_err(|err| TraceError::Other(Box::new(err)))?;
+        // The batch span processor spawns its task onto the current runtime.
+        let _guard = runtime.enter();
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+            .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+        opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+        Ok(Telemetry { tracer, _runtime: runtime })
+    }
+
+    /// Exports the spans that are still buffered. Called before the process exits.
+    pub fn flush() {
+        opentelemetry::global::shutdown_tracer_provider();
+    }
+}
+
+/// Makes the span a child of the trace given in the request's `traceparent` header, if any. Does
+/// nothing if telemetry is not enabled.
+pub fn set_parent_from_headers(span: &Span, headers: &HeaderMap) {
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(parent);
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap<'a>);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get_one(key)
+    }
+
+    // Only used by propagators that read a variable set of headers, such as baggage, which are not
+    // installed. Rocket's header map can't lend out its names for long enough anyway.
+    fn keys(&self) -> Vec<&str> {
+        Vec::new()
+    }
+}
@@ -1,27 +1,85 @@
 use std::convert::TryFrom;
 
-use chrono::{DateTime, FixedOffset, NaiveDateTime};
-use postgres::types::ToSql;
-use serde::Deserialize;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, SecondsFormat, Utc};
+use postgres::types::{IsNull, ToSql};
+use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 use std::error::Error;
 
-#[derive(Debug, Deserialize, Clone, PartialEq, Eq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
 pub enum Type {
-    #[serde(rename = "bool")]
     Bool,
-    #[serde(rename = "i32")]
     I32,
-    #[serde(rename = "i64")]
     I64,
-    #[serde(rename = "f32")]
+    /// Unsigned 64-bit integer, stored as `NUMERIC` because Postgres has no unsigned types.
+    U64,
     F32,
-    #[serde(rename = "f64")]
     F64,
-    #[serde(rename = "string")]
     String,
-    #[serde(rename = "timestamp")]
+    /// Fixed-length string of the given number of characters, written as `char(n)`.
+    Char(u32),
     Timestamp,
+    /// Wall-clock time without a time zone, stored as `TIMESTAMP`, written as `timestamp_naive`.
+    TimestampNaive,
+    Interval,
+    /// Arbitrary JSON, stored as `JSONB`.
+    Jsonb,
+    /// UUID in its usual hexadecimal form, stored as `UUID`.
+    Uuid,
+    /// Postgres enum type declared in the schema, written as `enum(name)`.
+    Enum(String),
+}
+
+/// Longest length that Postgres supports for `CHAR(n)`.
+const MAX_CHAR_LENGTH: u32 = 10_485_760;
+
+impl TryFrom<String> for Type {
+    type Error = String;
+    fn try_from(name: String) -> Result<Type, String> {
+        match name.as_str() {
+            "bool" => Ok(Type::Bool),
+            "i32" => Ok(Type::I32),
+            "i64" => Ok(Type::I64),
+            "u64" => Ok(Type::U64),
+            "f32" => Ok(Type::F32),
+            "f64" => Ok(Type::F64),
+            "string" => Ok(Type::String),
+            "timestamp" => Ok(Type::Timestamp),
+            "timestamp_naive" => Ok(Type::TimestampNaive),
+            "interval" => Ok(Type::Interval),
+            "jsonb" => Ok(Type::Jsonb),
+            "uuid" => Ok(Type::Uuid),
+            _ => name.strip_prefix("char(").and_then(|rest| rest.strip_suffix(')'))
+                .and_then(|length| length.trim().parse::<u32>().ok())
+                .filter(|length| (1..=MAX_CHAR_LENGTH).contains(length))
+                .map(Type::Char)
+                .or_else(|| name.strip_prefix("enum(").and_then(|rest| rest.strip_suffix(')'))
+                    .map(|enum_name| Type::Enum(enum_name.trim().to_string())))
+                .ok_or_else(|| format!("unknown type {}, expected one of bool, i32, i64, u64, f32, f64, string, char(n), timestamp, timestamp_naive, interval, jsonb, uuid, enum(name)", name)),
+        }
+    }
+}
+
+impl From<Type> for String {
+    fn from(type_: Type) -> String {
+        match type_ {
+            Type::Bool => "bool".to_string(),
+            Type::I32 => "i32".to_string(),
+            Type::I64 => "i64".to_string(),
+            Type::U64 => "u64".to_string(),
+            Type::F32 => "f32".to_string(),
+            Type::F64 => "f64".to_string(),
+            Type::String => "string".to_string(),
+            Type::Char(length) => format!("char({})", length),
+            Type::Timestamp => "timestamp".to_string(),
+            Type::TimestampNaive => "timestamp_naive".to_string(),
+            Type::Interval => "interval".to_string(),
+            Type::Jsonb => "jsonb".to_string(),
+            Type::Uuid => "uuid".to_string(),
+            Type::Enum(name) => format!("enum({})", name),
+        }
+    }
 }
 
 impl Default for Type {
@@ -33,14 +91,28 @@ impl Default for Type {
 #[derive(Debug, PartialEq, Eq)]
 pub enum ConversionError {
     MissingValue(String),
+    NotAllowed(String),
+    TooLong(String, u32),
+    NumberFormat(String),
+    Negative(String),
+    PrecisionLoss(String),
+    NonFinite(String),
     TimestampFormat(chrono::format::ParseError),
+    IntervalFormat(String),
 }
 
 impl Display for ConversionError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error> {
         match self {
             ConversionError::MissingValue(key) => write!(f, "required value \"{}\" was omitted", key),
+            ConversionError::NotAllowed(key) => write!(f, "field \"{}\" is not one of the allowed values", key),
+            ConversionError::TooLong(key, length) => write!(f, "field \"{}\" is longer than {} characters", key, length),
+            ConversionError::NumberFormat(value) => write!(f, "could not parse number \"{}\"", value),
+            ConversionError::Negative(key) => write!(f, "field \"{}\" must not be negative", key),
+            ConversionError::PrecisionLoss(key) => write!(f, "field \"{}\" cannot be stored as f32 without losing precision", key),
+            ConversionError::NonFinite(key) => write!(f, "field \"{}\" is NaN, infinite or out of range", key),
             ConversionError::TimestampFormat(err) => write!(f, "could not parse timestamp: {}", err),
+            ConversionError::IntervalFormat(value) => write!(f, "could not parse interval \"{}\": expected seconds or an ISO 8601 duration", value),
         }
     }
 }
@@ -48,8 +120,23 @@ impl Display for ConversionError {
 impl Error for ConversionError {}
 
 impl Type {
+    /// Returns the type as written in a column definition.
     pub fn postgres_type_name(&self) -> String {
-        self.postgres_type().name().to_string()
+        match self {
+            Type::Char(length) => format!("CHAR({})", length),
+            Type::Enum(name) => format!(r#""{}""#, name),
+            _ => self.postgres_type().name().to_string(),
+        }
+    }
+
+    /// Returns the modifier that Postgres stores along with the type in `pg_attribute.atttypmod`,
+    /// which is -1 if the type has no parameters.
+    pub fn postgres_type_modifier(&self) -> i32 {
+        match self {
+            // The length plus the size of the varlena header.
+            Type::Char(length) => *length as i32 + 4,
+            _ => -1,
+        }
     }
 
     pub fn postgres_type(&self) -> postgres::types::Type {
@@ -57,26 +144,150 @@ impl Type {
             Type::Bool => postgres::types::BOOL,
             Type::I32 => postgres::types::INT4,
             Type::I64 => postgres::types::INT8,
+            // The postgres crate can't convert NUMERIC, so values are sent as text and cast in the
+            // query.
+            Type::U64 => postgres::types::NUMERIC,
             Type::F32 => postgres::types::FLOAT4,
             Type::F64 => postgres::types::FLOAT8,
             Type::String => postgres::types::VARCHAR,
+            Type::Char(_) => postgres::types::BPCHAR,
             Type::Timestamp => postgres::types::TIMESTAMPTZ,
+            Type::TimestampNaive => postgres::types::TIMESTAMP,
+            Type::Interval => postgres::types::INTERVAL,
+            // Sent as text and cast in the query, because the postgres crate is built without
+            // serde_json support.
+            Type::Jsonb => postgres::types::JSONB,
+            // Likewise, because the postgres crate is built without uuid support.
+            Type::Uuid => postgres::types::UUID,
+            // Enum types get their OID when they are created, so values are sent as text and cast
+            // in the query; see `parameter_expression`.
+            Type::Enum(_) => postgres::types::TEXT,
+        }
+    }
+
+    /// Returns the SQL expression for the query parameter with the given number, which holds a
+    /// value of this type as converted by `json_to_sql`.
+    pub fn parameter_expression(&self, idx: usize) -> String {
+        match self {
+            Type::Enum(name) => format!(r#"${}::TEXT::"{}""#, idx, name),
+            Type::Jsonb => format!("${}::TEXT::JSONB", idx),
+            Type::U64 => format!("${}::TEXT::NUMERIC", idx),
+            Type::Uuid => format!("${}::TEXT::UUID", idx),
+            _ => format!("${}", idx),
+        }
+    }
+
+    /// Returns whether the JSON value can be stored in a column of this type. Used to check the
+    /// `allowed_values` of a column; timestamps are not supported there.
+    pub fn accepts(&self, json: &serde_json::Value) -> bool {
+        match self {
+            Type::Bool => json.is_boolean(),
+            Type::I32 => json.as_i64().map_or(false, |i| i32::try_from(i).is_ok()),
+            Type::I64 => json.is_i64(),
+            Type::U64 => json.is_u64(),
+            Type::F32 | Type::F64 => json.is_number(),
+            Type::String | Type::Enum(_) => json.is_string(),
+            Type::Char(length) => json.as_str().map_or(false, |s| s.chars().count() <= *length as usize),
+            Type::Uuid => json.as_str().map_or(false, is_uuid),
+            Type::Timestamp | Type::TimestampNaive | Type::Interval | Type::Jsonb => false,
+        }
+    }
+
+    /// Converts the value at the given index in a row, as selected by `select_expression`, to JSON.
+    pub fn row_to_json(&self, row: &postgres::rows::Row, idx: usize) -> serde_json::Value {
+        match self {
+            Type::Bool => serde_json::json!(row.get::<_, Option<bool>>(idx)),
+            Type::I32 => serde_json::json!(row.get::<_, Option<i32>>(idx)),
+            Type::I64 => serde_json::json!(row.get::<_, Option<i64>>(idx)),
+            Type::U64 => serde_json::json!(row.get::<_, Option<String>>(idx).and_then(|u| u.parse::<u64>().ok())),
+            Type::F32 => serde_json::json!(row.get::<_, Option<f32>>(idx)),
+            Type::F64 | Type::Interval => serde_json::json!(row.get::<_, Option<f64>>(idx)),
+            Type::String | Type::Char(_) | Type::Enum(_) | Type::Uuid => serde_json::json!(row.get::<_, Option<String>>(idx)),
+            Type::Timestamp => serde_json::json!(row.get::<_, Option<DateTime<Utc>>>(idx)
+                .map(|time| time.to_rfc3339_opts(SecondsFormat::AutoSi, true))),
+            Type::TimestampNaive => serde_json::json!(row.get::<_, Option<NaiveDateTime>>(idx)
+                .map(|time| time.format(NAIVE_DATE_TIME_FORMAT).to_string())),
+            Type::Jsonb => row.get::<_, Option<String>>(idx)
+                .and_then(|json| serde_json::from_str(&json).ok())
+                .unwrap_or(serde_json::Value::Null),
         }
     }
 
-    pub fn json_to_sql(&self, key: &str, json: &serde_json::Value, required: bool) -> Result<Box<ToSql>, ConversionError> {
+    /// Returns the SQL expression that selects the given column in a form that `row_to_json` can
+    /// read. Intervals are selected as a number of seconds, the same as is accepted on input.
+    pub fn select_expression(&self, column_name: &str) -> String {
+        match self {
+            Type::Interval => format!(r#"EXTRACT(EPOCH FROM "{}")::DOUBLE PRECISION"#, column_name),
+            Type::U64 | Type::Enum(_) | Type::Jsonb | Type::Uuid => format!(r#""{}"::TEXT"#, column_name),
+            _ => format!(r#""{}""#, column_name),
+        }
+    }
+
+    /// Converts the JSON value to a value of this type. NaN and infinite values of `f32` and `f64`
+    /// columns, which clients can send as strings such as `"NaN"` or `"Infinity"`, are rejected
+    /// unless `allow_non_finite` is set.
+    pub fn json_to_sql(&self, key: &str, json: &serde_json::Value, required: bool, allow_non_finite: bool) -> Result<Box<ToSql>, ConversionError> {
         match self {
             Type::Bool => unwrap_if_required(key, json.as_bool(), required),
-            Type::I32 => unwrap_if_required(key, json.as_i64().map(|i| i32::try_from(i).ok()), required),
-            Type::I64 => unwrap_if_required(key, json.as_i64(), required),
-            Type::F32 => unwrap_if_required(key, json.as_f64().map(|f| f as f32), required),
-            Type::F64 => unwrap_if_required(key, json.as_f64(), required),
-            Type::String => unwrap_if_required(key, json.as_str().map(|s| s.to_string()), required),
+            Type::I32 => unwrap_if_required(key, json_to_i64(json)?.map(|i| i32::try_from(i).ok()), required),
+            Type::I64 => unwrap_if_required(key, json_to_i64(json)?, required),
+            Type::U64 => unwrap_if_required(key, json_to_u64(key, json)?.map(|u| u.to_string()), required),
+            Type::F32 => unwrap_if_required(key, json_to_f32(key, json, allow_non_finite)?, required),
+            Type::F64 => unwrap_if_required(key, json_to_f64(key, json, allow_non_finite)?, required),
+            // Invalid enum values and UUIDs are rejected by Postgres.
+            Type::String | Type::Enum(_) | Type::Uuid => unwrap_if_required(key, json.as_str().map(|s| s.to_string()), required),
+            Type::Char(length) => {
+                // Shorter strings are padded with spaces by Postgres.
+                let value = json.as_str().map(|s| s.to_string());
+                if value.as_ref().map_or(false, |s| s.chars().count() > *length as usize) {
+                    return Err(ConversionError::TooLong(key.to_string(), *length));
+                }
+                unwrap_if_required(key, value, required)
+            }
             Type::Timestamp => unwrap_if_required(key, json_to_date_time(json)?, required),
+            Type::TimestampNaive => unwrap_if_required(key, json_to_naive_date_time(json)?, required),
+            Type::Interval => unwrap_if_required(key, json_to_interval(json)?, required),
+            Type::Jsonb => unwrap_if_required(key, Some(json).filter(|json| !json.is_null()).map(|json| json.to_string()), required),
         }
     }
 }
 
+/// Returns whether the value is one of the allowed values. Numbers compare by value, so `1` and
+/// `1.0` are considered equal.
+pub fn is_allowed(allowed_values: &[serde_json::Value], json: &serde_json::Value) -> bool {
+    allowed_values.iter().any(|allowed| match (allowed.as_f64(), json.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => allowed == json,
+    })
+}
+
+/// Returns whether the string is a UUID in its usual form of 32 hexadecimal digits in groups of
+/// 8, 4, 4, 4 and 12, separated by dashes.
+fn is_uuid(s: &str) -> bool {
+    s.len() == 36 && s.bytes().enumerate().all(|(idx, b)| match idx {
+        8 | 13 | 18 | 23 => b == b'-',
+        _ => b.is_ascii_hexdigit(),
+    })
+}
+
+/// Returns a new random (version 4) UUID.
+pub fn random_uuid() -> String {
+    let mut bytes = rand::random::<[u8; 16]>();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+}
+
+/// Formats a value from `allowed_values` as an SQL literal.
+pub fn json_to_sql_literal(json: &serde_json::Value) -> String {
+    match json {
+        serde_json::Value::String(s) => format!("'{}'", s.replace('\'', "''")),
+        serde_json::Value::Bool(b) => if *b { "TRUE" } else { "FALSE" }.to_string(),
+        _ => json.to_string(),
+    }
+}
+
 pub fn header_to_sql<'a>(key: &str, value: Option<&'a str>, required: bool) -> Result<Box<ToSql + 'a>, ConversionError> {
     unwrap_if_required(key, value, required)
 }
@@ -91,7 +302,61 @@ pub fn unwrap_if_required<'a, T>(key: &str, option: Option<T>, required: bool) -
     }
 }
 
-fn json_to_date_time(json: &serde_json::Value) -> Result<Option<DateTime<FixedOffset>>, ConversionError> {
+/// Converts a JSON integer, or a string containing one, to an `i64`.
+fn json_to_i64(json: &serde_json::Value) -> Result<Option<i64>, ConversionError> {
+    match json.as_str() {
+        Some(string) => string.parse::<i64>().map(Some).map_err(|_| ConversionError::NumberFormat(string.to_string())),
+        None => Ok(json.as_i64()),
+    }
+}
+
+/// Converts a JSON integer, or a string containing one, to a `u64`. Unlike with `json_to_i64`,
+/// numbers that don't fit are an error rather than a missing value.
+fn json_to_u64(key: &str, json: &serde_json::Value) -> Result<Option<u64>, ConversionError> {
+    let string = match json {
+        serde_json::Value::Null => return Ok(None),
+        serde_json::Value::Number(number) => number.to_string(),
+        serde_json::Value::String(string) => string.to_string(),
+        _ => return Ok(None),
+    };
+    string.parse::<u64>().map(Some).map_err(|_| match string.parse::<f64>() {
+        Ok(f) if f < 0.0 => ConversionError::Negative(key.to_string()),
+        _ => ConversionError::NumberFormat(string),
+    })
+}
+
+/// Converts a JSON number, or a string containing one, to an `f64`. Strings can spell NaN and
+/// infinity, which are an error unless `allow_non_finite` is set.
+fn json_to_f64(key: &str, json: &serde_json::Value, allow_non_finite: bool) -> Result<Option<f64>, ConversionError> {
+    let f = match json.as_str() {
+        Some(string) => string.parse::<f64>().map(Some).map_err(|_| ConversionError::NumberFormat(string.to_string()))?,
+        None => json.as_f64(),
+    };
+    match f {
+        Some(f) if !f.is_finite() && !allow_non_finite => Err(ConversionError::NonFinite(key.to_string())),
+        _ => Ok(f),
+    }
+}
+
+/// Like `json_to_f64`, but also treats finite numbers that are too large for an `f32` as infinite.
+fn json_to_f32(key: &str, json: &serde_json::Value, allow_non_finite: bool) -> Result<Option<f32>, ConversionError> {
+    match json_to_f64(key, json, allow_non_finite)?.map(|f| f as f32) {
+        Some(f) if !f.is_finite() && !allow_non_finite => Err(ConversionError::NonFinite(key.to_string())),
+        f => Ok(f),
+    }
+}
+
+/// Returns an error if the number has more significant digits than an `f32` can hold, or is out of
+/// its range. The usual rounding of decimal fractions, such as 0.1, is not considered a loss,
+/// because the `f32` still reads back as the same decimal number.
+pub fn check_f32_precision(key: &str, json: &serde_json::Value) -> Result<(), ConversionError> {
+    match json_to_f64(key, json, true)? {
+        Some(f) if f.is_finite() && (f as f32).to_string().parse::<f64>() != Ok(f) => Err(ConversionError::PrecisionLoss(key.to_string())),
+        _ => Ok(()),
+    }
+}
+
+pub fn json_to_date_time(json: &serde_json::Value) -> Result<Option<DateTime<FixedOffset>>, ConversionError> {
     if json.is_number() {
         let timestamp = json.as_f64().unwrap();
         let naive = NaiveDateTime::from_timestamp(timestamp.floor() as i64, (1e9 * timestamp.fract()) as u32);
@@ -103,3 +368,242 @@ fn json_to_date_time(json: &serde_json::Value) -> Result<Option<DateTime<FixedOf
         Ok(None)
     }
 }
+
+/// Format in which `timestamp_naive` values are accepted and returned, like RFC 3339 without the
+/// offset.
+const NAIVE_DATE_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.f";
+
+/// Converts seconds since the Unix epoch, or a string like `2019-04-01T14:49:40`, to a date and
+/// time without a time zone. If the string has an offset, it is ignored rather than converted, so
+/// the wall-clock time is kept as the client wrote it.
+pub fn json_to_naive_date_time(json: &serde_json::Value) -> Result<Option<NaiveDateTime>, ConversionError> {
+    match json.as_str() {
+        Some(string) => NaiveDateTime::parse_from_str(string, NAIVE_DATE_TIME_FORMAT)
+            .or_else(|err| DateTime::parse_from_rfc3339(string).map(|time| time.naive_local()).map_err(|_| err))
+            .map(Some)
+            .map_err(ConversionError::TimestampFormat),
+        None => Ok(json_to_date_time(json)?.map(|time| time.naive_utc())),
+    }
+}
+
+/// A Postgres `INTERVAL`. Months and days are kept separately from the rest, because their length
+/// in seconds varies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub months: i32,
+    pub days: i32,
+    pub microseconds: i64,
+}
+
+impl ToSql for Interval {
+    fn to_sql(&self, _ty: &postgres::types::Type, out: &mut Vec<u8>) -> Result<IsNull, Box<Error + Sync + Send>> {
+        out.extend_from_slice(&self.microseconds.to_be_bytes());
+        out.extend_from_slice(&self.days.to_be_bytes());
+        out.extend_from_slice(&self.months.to_be_bytes());
+        Ok(IsNull::No)
+    }
+
+    fn accepts(ty: &postgres::types::Type) -> bool {
+        *ty == postgres::types::INTERVAL
+    }
+
+    // The postgres crate doesn't export its to_sql_checked! macro, so this is what it expands to.
+    fn to_sql_checked(&self, ty: &postgres::types::Type, out: &mut Vec<u8>) -> Result<IsNull, Box<Error + Sync + Send>> {
+        if !<Interval as ToSql>::accepts(ty) {
+            return Err(format!("cannot convert an interval to type {}", ty).into());
+        }
+        self.to_sql(ty, out)
+    }
+}
+
+/// Converts a number of seconds, or an ISO 8601 duration string like `PT1H30M`, to an interval.
+pub fn json_to_interval(json: &serde_json::Value) -> Result<Option<Interval>, ConversionError> {
+    if let Some(seconds) = json.as_f64() {
+        Ok(Some(Interval { months: 0, days: 0, microseconds: (seconds * 1e6).round() as i64 }))
+    } else if let Some(string) = json.as_str() {
+        parse_iso8601_duration(string)
+            .map(Some)
+            .ok_or_else(|| ConversionError::IntervalFormat(string.to_string()))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parses durations of the form `PnYnMnWnDTnHnMnS`, where every part is optional but at least one
+/// must be present. Only the seconds may have a fraction. A leading `-` negates the duration.
+fn parse_iso8601_duration(string: &str) -> Option<Interval> {
+    let (sign, rest) = match string.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, string),
+    };
+    let rest = rest.strip_prefix('P')?;
+    let (date, time) = match rest.find('T') {
+        Some(index) => (&rest[..index], Some(&rest[index + 1..])),
+        None => (rest, None),
+    };
+    let mut interval = Interval { months: 0, days: 0, microseconds: 0 };
+    let mut any_parts = false;
+    for (number, unit) in duration_parts(date, "YMWD")? {
+        let number = number.parse::<i32>().ok()?;
+        match unit {
+            'Y' => interval.months = interval.months.checked_add(number.checked_mul(12)?)?,
+            'M' => interval.months = interval.months.checked_add(number)?,
+            'W' => interval.days = interval.days.checked_add(number.checked_mul(7)?)?,
+            'D' => interval.days = interval.days.checked_add(number)?,
+            _ => return None,
+        }
+        any_parts = true;
+    }
+    if let Some(time) = time {
+        let parts = duration_parts(time, "HMS")?;
+        if parts.is_empty() {
+            return None;
+        }
+        for (number, unit) in parts {
+            let seconds_per_unit = match unit {
+                'H' => 3600.0,
+                'M' => 60.0,
+                _ => 1.0,
+            };
+            if unit != 'S' && number.contains('.') {
+                return None;
+            }
+            // Casting would saturate, rather than fail, if the number is too large.
+            let microseconds = (number.parse::<f64>().ok()? * seconds_per_unit * 1e6).round();
+            if !(microseconds < i64::MAX as f64) {
+                return None;
+            }
+            interval.microseconds = interval.microseconds.checked_add(microseconds as i64)?;
+        }
+        any_parts = true;
+    }
+    if !any_parts {
+        return None;
+    }
+    Some(Interval { months: sign * interval.months, days: sign * interval.days, microseconds: sign as i64 * interval.microseconds })
+}
+
+/// Splits a string like `1Y2M` into pairs like `("1", 'Y')`. Each unit must be one of `units`,
+/// and they must occur at most once and in the same order as there.
+fn duration_parts<'a>(string: &'a str, units: &str) -> Option<Vec<(&'a str, char)>> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut remaining_units = units;
+    for (index, c) in string.char_indices() {
+        if c.is_ascii_alphabetic() {
+            if index == start {
+                return None;
+            }
+            remaining_units = &remaining_units[remaining_units.find(c)? + 1..];
+            parts.push((&string[start..index], c));
+            start = index + 1;
+        } else if !(c.is_ascii_digit() || c == '.') {
+            return None;
+        }
+    }
+    if start != string.len() {
+        return None;
+    }
+    Some(parts)
+}
+
+#[test]
+fn parse_char_type() {
+    assert_eq!(Type::try_from("char(2)".to_string()), Ok(Type::Char(2)));
+    assert_eq!(String::from(Type::Char(2)), "char(2)");
+    assert!(Type::try_from("char(0)".to_string()).is_err());
+    assert!(Type::try_from("char".to_string()).is_err());
+    assert!(Type::Char(2).json_to_sql("country", &serde_json::json!("NL"), true, false).is_ok());
+    assert_eq!(Type::Char(2).json_to_sql("country", &serde_json::json!("NLD"), true, false).err(),
+               Some(ConversionError::TooLong("country".to_string(), 2)));
+}
+
+#[test]
+fn parse_naive_timestamps() {
+    let time = |s: &str| Some(NaiveDateTime::parse_from_str(s, NAIVE_DATE_TIME_FORMAT).unwrap());
+    assert_eq!(json_to_naive_date_time(&serde_json::json!("2019-04-01T14:49:40")), Ok(time("2019-04-01T14:49:40")));
+    assert_eq!(json_to_naive_date_time(&serde_json::json!("2019-04-01T14:49:40.25+02:00")), Ok(time("2019-04-01T14:49:40.25")));
+    assert_eq!(json_to_naive_date_time(&serde_json::json!(1554130180)), Ok(time("2019-04-01T14:49:40")));
+    assert_eq!(json_to_naive_date_time(&serde_json::json!(null)), Ok(None));
+    assert!(json_to_naive_date_time(&serde_json::json!("yesterday")).is_err());
+    assert_eq!(Type::try_from("timestamp_naive".to_string()), Ok(Type::TimestampNaive));
+    assert_eq!(Type::TimestampNaive.postgres_type_name(), "timestamp");
+}
+
+#[test]
+fn parse_intervals() {
+    let interval = |months, days, microseconds| Some(Interval { months, days, microseconds });
+    assert_eq!(json_to_interval(&serde_json::json!(90.5)), Ok(interval(0, 0, 90_500_000)));
+    assert_eq!(json_to_interval(&serde_json::json!("PT1H30M")), Ok(interval(0, 0, 5_400_000_000)));
+    assert_eq!(json_to_interval(&serde_json::json!("P1Y2M3W4DT0.25S")), Ok(interval(14, 25, 250_000)));
+    assert_eq!(json_to_interval(&serde_json::json!("-P1D")), Ok(interval(0, -1, 0)));
+    assert_eq!(json_to_interval(&serde_json::json!(null)), Ok(None));
+    assert_eq!(json_to_interval(&serde_json::json!("PT2562047788H")), Ok(interval(0, 0, 2_562_047_788 * 3_600_000_000)));
+    for invalid in &["", "P", "PT", "1H", "P1H", "PT1D", "PT1.5H", "P1Y2", "PxD", "P1D1D", "P1D1Y", "PT1M1H",
+                     "PT2562047789H", "PT2562047788H3600S", "PT9999999999999999999H9999999999999999999H"] {
+        assert_eq!(json_to_interval(&serde_json::json!(invalid)), Err(ConversionError::IntervalFormat(invalid.to_string())));
+    }
+}
+
+#[test]
+fn parse_numeric_strings() {
+    assert_eq!(json_to_i64(&serde_json::json!("-42")), Ok(Some(-42)));
+    assert_eq!(json_to_i64(&serde_json::json!(42)), Ok(Some(42)));
+    assert_eq!(json_to_i64(&serde_json::json!("4.2")), Err(ConversionError::NumberFormat("4.2".to_string())));
+    assert_eq!(json_to_f64("x", &serde_json::json!("2.5"), false), Ok(Some(2.5)));
+    assert_eq!(json_to_f64("x", &serde_json::json!(null), false), Ok(None));
+    assert_eq!(json_to_f64("x", &serde_json::json!("pi"), false), Err(ConversionError::NumberFormat("pi".to_string())));
+}
+
+#[test]
+fn parse_u64() {
+    assert_eq!(json_to_u64("x", &serde_json::json!(18446744073709551615u64)), Ok(Some(u64::MAX)));
+    assert_eq!(json_to_u64("x", &serde_json::json!("18446744073709551615")), Ok(Some(u64::MAX)));
+    assert_eq!(json_to_u64("x", &serde_json::json!(null)), Ok(None));
+    assert_eq!(json_to_u64("x", &serde_json::json!(-1)), Err(ConversionError::Negative("x".to_string())));
+    assert_eq!(json_to_u64("x", &serde_json::json!("-1")), Err(ConversionError::Negative("x".to_string())));
+    assert_eq!(json_to_u64("x", &serde_json::json!("18446744073709551616")), Err(ConversionError::NumberFormat("18446744073709551616".to_string())));
+    assert_eq!(json_to_u64("x", &serde_json::json!(1.5)), Err(ConversionError::NumberFormat("1.5".to_string())));
+}
+
+#[test]
+fn f32_precision() {
+    assert_eq!(check_f32_precision("x", &serde_json::json!(0.1)), Ok(()));
+    assert_eq!(check_f32_precision("x", &serde_json::json!(16777216)), Ok(()));
+    assert_eq!(check_f32_precision("x", &serde_json::json!(null)), Ok(()));
+    for lossy in &[serde_json::json!(16777217), serde_json::json!(0.1 + 0.2), serde_json::json!(1e300)] {
+        assert_eq!(check_f32_precision("x", lossy), Err(ConversionError::PrecisionLoss("x".to_string())));
+    }
+}
+
+#[test]
+fn finite_floats() {
+    assert_eq!(json_to_f64("x", &serde_json::json!(1e300), false), Ok(Some(1e300)));
+    assert_eq!(json_to_f64("x", &serde_json::json!("-2.5"), false), Ok(Some(-2.5)));
+    assert_eq!(json_to_f64("x", &serde_json::json!(null), false), Ok(None));
+    assert!(Type::String.json_to_sql("x", &serde_json::json!("NaN"), true, false).is_ok());
+    for non_finite in &["NaN", "inf", "-Infinity"] {
+        assert_eq!(json_to_f64("x", &serde_json::json!(non_finite), false), Err(ConversionError::NonFinite("x".to_string())));
+        assert!(json_to_f64("x", &serde_json::json!(non_finite), true).unwrap().map_or(false, |f| !f.is_finite()));
+    }
+    assert_eq!(json_to_f32("x", &serde_json::json!(1e300), false), Err(ConversionError::NonFinite("x".to_string())));
+    assert_eq!(json_to_f32("x", &serde_json::json!(1e300), true), Ok(Some(f32::INFINITY)));
+}
+
+#[test]
+fn random_uuids() {
+    let uuid = random_uuid();
+    assert!(is_uuid(&uuid));
+    assert_eq!(&uuid[14..15], "4");
+    assert!("89ab".contains(&uuid[19..20]));
+    assert_ne!(uuid, random_uuid());
+    assert!(Type::Uuid.accepts(&serde_json::json!("123e4567-E89B-12d3-a456-426614174000")));
+    assert!(!Type::Uuid.accepts(&serde_json::json!("123e4567e89b12d3a456426614174000")));
+}
+
+#[test]
+fn conversion_error_messages() {
+    assert_eq!(ConversionError::NotAllowed("platform".to_string()).to_string(), "field \"platform\" is not one of the allowed values");
+    assert_eq!(ConversionError::TooLong("country".to_string(), 2).to_string(), "field \"country\" is longer than 2 characters");
+    assert_eq!(ConversionError::Negative("score".to_string()).to_string(), "field \"score\" must not be negative");
+}
@@ -0,0 +1,69 @@
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::db;
+use crate::queue::RetryQueue;
+use crate::schema::App;
+
+/// Maximum number of events waiting to be forwarded. Beyond this, events are dropped.
+const QUEUE_SIZE: usize = 10000;
+/// Number of times to try delivering each event.
+const MAX_ATTEMPTS: u32 = 3;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize)]
+struct Payload {
+    app_id: String,
+    table: String,
+    event: serde_json::Value,
+}
+
+#[derive(Debug)]
+struct Delivery {
+    url: String,
+    payload: Payload,
+}
+
+impl Display for Delivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "event for table {} of app {} to {}", self.payload.table, self.payload.app_id, self.url)
+    }
+}
+
+/// Forwards inserted events to the `webhook_url` of their app, from a background thread.
+#[derive(Debug)]
+pub struct Webhooks {
+    queue: RetryQueue<Delivery>,
+}
+
+impl Webhooks {
+    /// Creates the queue and starts the thread that delivers from it.
+    pub fn start() -> Result<Webhooks, reqwest::Error> {
+        let client = reqwest::blocking::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let queue = RetryQueue::start("webhook", QUEUE_SIZE, MAX_ATTEMPTS, move |delivery: &Delivery| {
+            client.post(&delivery.url)
+                .json(&delivery.payload)
+                .send()
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+        });
+        Ok(Webhooks { queue })
+    }
+
+    /// Queues the event for forwarding once for each table it was inserted into, if the app has a
+    /// webhook.
+    pub fn send(&self, app: &App, event: &serde_json::Value) {
+        let url = match &app.webhook_url {
+            Some(url) => url,
+            None => return,
+        };
+        for table_name in db::table_names(event).unwrap_or_default() {
+            self.queue.push(Delivery {
+                url: url.to_string(),
+                payload: Payload { app_id: app.app_id.to_string(), table: table_name.to_string(), event: event.clone() },
+            });
+        }
+    }
+}